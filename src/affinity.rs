@@ -0,0 +1,76 @@
+//! CPU affinity helpers, used to pin worker threads to specific CPUs so that
+//! results are less affected by the scheduler moving threads around.
+
+use std::{io, mem, str::FromStr};
+
+use crate::errors::AppError;
+
+/// A list of CPUs, parsed from a string like `0-3,8,10-11`.
+///
+/// Threads are assigned to CPUs in the order they appear in the list, wrapping
+/// around if there are more threads than CPUs.
+#[derive(Debug, Clone)]
+pub struct CpuList(Vec<usize>);
+
+impl FromStr for CpuList {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+      let part = part.trim();
+      let (start, end) = match part.split_once('-') {
+        Some((start, end)) => (start, end),
+        None => (part, part),
+      };
+      let start: usize = start.trim().parse().map_err(|_| "Invalid CPU number")?;
+      let end: usize = end.trim().parse().map_err(|_| "Invalid CPU number")?;
+      if start > end {
+        return Err("Invalid CPU range");
+      }
+      if end >= libc::CPU_SETSIZE as usize {
+        return Err("CPU number too large");
+      }
+      cpus.extend(start..=end);
+    }
+    Ok(Self(cpus))
+  }
+}
+
+impl CpuList {
+  /// Get the CPU that the `n`-th spawned thread should be pinned to.
+  pub fn cpu_for_thread(&self, n: usize) -> usize {
+    self.0[n % self.0.len()]
+  }
+
+  /// Check that every CPU in the list is one this process is allowed to run
+  /// on, so that we can fail early instead of in the middle of spawning
+  /// threads.
+  pub fn check_available(&self) -> Result<(), AppError> {
+    let set = unsafe {
+      let mut set: libc::cpu_set_t = mem::zeroed();
+      if libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) == -1 {
+        return Err(AppError::IOError("sched_getaffinity", io::Error::last_os_error()));
+      }
+      set
+    };
+    for &cpu in self.0.iter() {
+      if !unsafe { libc::CPU_ISSET(cpu, &set) } {
+        return Err(AppError::CpuNotAvailable(cpu));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Pin the calling thread to the given CPU.
+pub fn pin_current_thread(cpu: usize) -> Result<(), AppError> {
+  unsafe {
+    let mut set: libc::cpu_set_t = mem::zeroed();
+    libc::CPU_SET(cpu, &mut set);
+    if libc::sched_setaffinity(0, mem::size_of_val(&set), &set) == -1 {
+      return Err(AppError::IOError("sched_setaffinity", io::Error::last_os_error()));
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,237 @@
+//! Scenario files, holding the same settings as the command line in TOML or
+//! JSON, so that the runs of a test plan can be kept and replayed.
+//!
+//! A scenario is a flat table.  `mode` is the subcommand, and every other key
+//! is the name of one of its flags or positional arguments, with `_` or `-` as
+//! separator:
+//!
+//! ```toml
+//! mode = "syscall-send"
+//! server_addr = "10.0.0.2:9000"
+//! packet_size = 1200
+//! nb_sockets = 4
+//! stats_file = "run.csv"
+//! ```
+//!
+//! The file is applied by turning it back into command line arguments, so
+//! values are validated exactly like flags, and flags given on the command line
+//! take precedence.
+
+use std::{
+  collections::BTreeMap,
+  ffi::{OsStr, OsString},
+  fs,
+  path::{Path, PathBuf},
+  process,
+};
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches};
+
+use crate::errors::AppError;
+
+/// Flags about handling scenarios, rather than part of them.
+const META_ARGS: [&str; 4] = ["help", "version", "config", "dump_config"];
+
+/// Parse the command line, merged with the scenario file given with
+/// `--config`, if any.  With `--dump-config`, print the resulting settings as
+/// a scenario file and exit.
+pub fn parse<T: CommandFactory + FromArgMatches>() -> Result<T, AppError> {
+  let args: Vec<OsString> = std::env::args_os().collect();
+  // The mode and required arguments may come from the file, so only check
+  // them once it is merged in.
+  let cli_matches = relaxed(T::command()).get_matches_from(&args);
+  let matches = match cli_matches.get_one::<PathBuf>("config") {
+    Some(path) => {
+      let argv = merge(&built_command::<T>(), &cli_matches, path, args[0].clone())?;
+      T::command().get_matches_from(argv)
+    }
+    None => T::command().get_matches_from(&args),
+  };
+  if cli_matches.get_flag("dump_config") {
+    print!("{}", dump(&built_command::<T>(), &matches));
+    process::exit(0);
+  }
+  Ok(T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/// The command with global arguments propagated to the subcommands.
+fn built_command<T: CommandFactory>() -> Command {
+  let mut cmd = T::command();
+  cmd.build();
+  cmd
+}
+
+/// Make the subcommand and all arguments optional.
+fn relaxed(cmd: Command) -> Command {
+  let names: Vec<String> = cmd.get_subcommands().map(|s| s.get_name().to_owned()).collect();
+  let mut cmd = cmd.subcommand_required(false).arg_required_else_help(false);
+  for name in names {
+    cmd = cmd.mut_subcommand(name, |mut sub| {
+      let required: Vec<String> = sub
+        .get_arguments()
+        .filter(|a| a.is_required_set())
+        .map(|a| a.get_id().to_string())
+        .collect();
+      for id in required {
+        sub = sub.mut_arg(id, |a| a.required(false));
+      }
+      sub
+    });
+  }
+  cmd
+}
+
+/// Read a scenario file into a map of argument ids to values.
+fn read_scenario(path: &Path) -> Result<BTreeMap<String, String>, AppError> {
+  let error = |msg: String| AppError::ConfigError(path.display().to_string(), msg);
+  let content =
+    fs::read_to_string(path).map_err(|e| AppError::IOError("read scenario file", e))?;
+  let mut values = BTreeMap::new();
+  let mut insert = |key: String, value: Option<String>| match value {
+    Some(value) => {
+      values.insert(key.replace('-', "_"), value);
+      Ok(())
+    }
+    None => Err(error(format!("{key}: expected a string, number or boolean"))),
+  };
+  if path.extension().is_some_and(|e| e == "json") {
+    let root: serde_json::Value =
+      serde_json::from_str(&content).map_err(|e| error(e.to_string()))?;
+    let serde_json::Value::Object(object) = root else {
+      return Err(error("expected an object".to_owned()));
+    };
+    for (key, value) in object {
+      let value = match value {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+      };
+      insert(key, value)?;
+    }
+  } else {
+    let table: toml::Table = content.parse().map_err(|e: toml::de::Error| error(e.to_string()))?;
+    for (key, value) in table {
+      let value = match value {
+        toml::Value::String(s) => Some(s),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+      };
+      insert(key, value)?;
+    }
+  }
+  Ok(values)
+}
+
+/// Values of an argument, if it was given on the command line.
+fn explicit_values(matches: &ArgMatches, id: &str) -> Option<Vec<OsString>> {
+  // try_get_raw fails for ids that don't belong to these matches, where
+  // value_source would panic.
+  let values = matches.try_get_raw(id).ok()??;
+  if matches.value_source(id) != Some(ValueSource::CommandLine) {
+    return None;
+  }
+  Some(values.map(OsStr::to_owned).collect())
+}
+
+/// Build the arguments for the run, from the scenario file at `path` and the
+/// arguments given on the command line.
+fn merge(
+  cmd: &Command,
+  cli: &ArgMatches,
+  path: &Path,
+  program: OsString,
+) -> Result<Vec<OsString>, AppError> {
+  let error = |msg: String| AppError::ConfigError(path.display().to_string(), msg);
+  let mut scenario = read_scenario(path)?;
+  let file_mode = scenario.remove("mode");
+  let (mode, cli) = match cli.subcommand() {
+    Some((name, sub_matches)) => (name.to_owned(), sub_matches),
+    None => (file_mode.ok_or_else(|| error("no mode given".to_owned()))?, cli),
+  };
+  let sub = cmd
+    .find_subcommand(&mode)
+    .ok_or_else(|| error(format!("unknown mode {mode:?}")))?;
+
+  let mut argv = vec![program, OsString::from(&mode)];
+  let mut positionals = Vec::new();
+  for arg in sub.get_arguments() {
+    let id = arg.get_id().as_str();
+    if META_ARGS.contains(&id) {
+      continue;
+    }
+    let file_value = scenario.remove(id);
+    let values = match (explicit_values(cli, id), file_value) {
+      (Some(values), _) => values,
+      (None, Some(value)) => vec![OsString::from(value)],
+      (None, None) => continue,
+    };
+    if arg.is_positional() {
+      positionals.extend(values);
+      continue;
+    }
+    // All our flags have a long name.
+    let long = arg.get_long().unwrap();
+    if let ArgAction::SetTrue = arg.get_action() {
+      if values.iter().any(|v| v == "true") {
+        argv.push(format!("--{long}").into());
+      }
+      continue;
+    }
+    for value in values {
+      let mut flag = OsString::from(format!("--{long}="));
+      flag.push(value);
+      argv.push(flag);
+    }
+  }
+  if let Some(key) = scenario.keys().next() {
+    return Err(error(format!("{key:?} is not an option of {mode}")));
+  }
+  if !positionals.is_empty() {
+    argv.push("--".into());
+    argv.extend(positionals);
+  }
+  Ok(argv)
+}
+
+/// Format an argument value for a scenario file, as a number or boolean when
+/// it looks like one.
+fn toml_value(value: String, action: &ArgAction) -> toml::Value {
+  if let ArgAction::SetTrue = action {
+    return toml::Value::Boolean(value == "true");
+  }
+  if let Ok(i) = value.parse::<i64>() {
+    return toml::Value::Integer(i);
+  }
+  // Integers past i64 stay strings rather than losing precision as floats.
+  if value.contains('.') {
+    if let Ok(f) = value.parse::<f64>() {
+      return toml::Value::Float(f);
+    }
+  }
+  toml::Value::String(value)
+}
+
+/// Print the effective settings of a run as a scenario file.
+fn dump(cmd: &Command, matches: &ArgMatches) -> String {
+  // The subcommand is required once the scenario is merged in.
+  let (mode, matches) = matches.subcommand().unwrap();
+  let sub = cmd.find_subcommand(mode).unwrap();
+  let mut out = format!("mode = {}\n", toml::Value::String(mode.to_owned()));
+  for arg in sub.get_arguments() {
+    let id = arg.get_id().as_str();
+    if META_ARGS.contains(&id) {
+      continue;
+    }
+    let Ok(Some(mut values)) = matches.try_get_raw(id) else {
+      continue;
+    };
+    if let Some(value) = values.next() {
+      let value = toml_value(value.to_string_lossy().into_owned(), arg.get_action());
+      out += &format!("{id} = {value}\n");
+    }
+  }
+  out
+}
@@ -0,0 +1,605 @@
+//! The control connection, a TCP connection over which the client announces
+//! the test parameters before sending any packets, and the server checks that
+//! it can honour them, similar to iperf's control session.  Without it,
+//! mismatched settings just look like 100% loss.
+//!
+//! Messages are JSON objects, one per line.  The client sends `hello` with the
+//! parameters, the server answers with `accept` or `reject`, and after an
+//! `accept` the client starts sending `start_delay_ms` later.  The client sends
+//! `done` at the end of the test, with the local ports of its data sockets, and
+//! the server answers with a `report` of how many packets from those ports it
+//! received and echoed.  Comparing that with its own counts, the client can
+//! tell whether packets were lost on the way out or on the way back.  The
+//! server's stats steps keep following its own clock, which started when it
+//! was launched, since it may serve several tests at once.
+
+use std::{
+  collections::HashMap,
+  io::{self, BufRead, BufReader, Write},
+  net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::errors::AppError;
+use crate::pkt::PacketFormat;
+
+/// Bumped whenever the messages change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// How long to wait for the other end before giving up.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the server checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the echo threads merge their counts into the [`PeerTable`].
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the client expects the server to do with its packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TestMode {
+  /// Send every packet back.
+  Echo,
+  /// Only receive, for one-way tests.
+  Sink,
+}
+
+/// Everything about a test that both ends need to agree on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestParams {
+  pub version: u32,
+  pub mode: TestMode,
+
+  /// The UDP port the client sends to.
+  pub data_port: u16,
+  pub packet_size: u32,
+  pub seed: u64,
+  #[serde(default)]
+  pub packet_format: PacketFormat,
+  pub nb_sockets: usize,
+
+  /// How long after the server accepts the test the client starts sending.
+  pub start_delay_ms: u64,
+}
+
+/// What an echo server did with the packets of one client.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PeerCounts {
+  pub rx_packets: u64,
+  pub rx_bytes: u64,
+
+  /// Packets sent back.
+  pub tx_packets: u64,
+  pub tx_errors: u64,
+}
+
+impl PeerCounts {
+  fn add(&mut self, other: &PeerCounts) {
+    self.rx_packets += other.rx_packets;
+    self.rx_bytes += other.rx_bytes;
+    self.tx_packets += other.tx_packets;
+    self.tx_errors += other.tx_errors;
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+  Hello { params: TestParams },
+  Done { ports: Vec<u16> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+  Accept { backend: String, mtu: usize },
+  Reject { reason: String },
+  Report { counts: PeerCounts },
+}
+
+/// A control connection, on either end.
+struct Connection {
+  stream: TcpStream,
+  reader: BufReader<TcpStream>,
+
+  /// Part of a line read before a timeout.
+  line: String,
+}
+
+impl Connection {
+  fn new(stream: TcpStream) -> Result<Self, AppError> {
+    let io_err = |e| AppError::IOError("control connection", e);
+    stream.set_nodelay(true).map_err(io_err)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(io_err)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(io_err)?;
+    let reader = BufReader::new(stream.try_clone().map_err(io_err)?);
+    Ok(Self {
+      stream,
+      reader,
+      line: String::new(),
+    })
+  }
+
+  fn send(&mut self, msg: &impl Serialize) -> Result<(), AppError> {
+    let mut line = serde_json::to_string(msg).unwrap();
+    line.push('\n');
+    self
+      .stream
+      .write_all(line.as_bytes())
+      .map_err(|e| AppError::IOError("control connection", e))
+  }
+
+  /// Read the next message, or `None` if the other end closed the connection.
+  /// Fails with `WouldBlock` or `TimedOut` if nothing came within the read
+  /// timeout, in which case it can be called again.
+  fn try_recv<T: DeserializeOwned>(&mut self) -> io::Result<Option<T>> {
+    if self.reader.read_line(&mut self.line)? == 0 {
+      return Ok(None);
+    }
+    let msg = serde_json::from_str(&self.line);
+    self.line.clear();
+    msg.map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  fn recv<T: DeserializeOwned>(&mut self) -> Result<T, AppError> {
+    match self.try_recv() {
+      Ok(Some(msg)) => Ok(msg),
+      Ok(None) => Err(AppError::ControlError("connection closed".to_owned())),
+      Err(e) => Err(AppError::IOError("control connection", e)),
+    }
+  }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+  matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Get the port of a host:port address.
+pub fn port_of(addr: &str) -> Result<u16, AppError> {
+  addr
+    .to_socket_addrs()
+    .map_err(|e| AppError::UnableToResolveNetAddr(addr.to_owned(), format!("{}", e)))?
+    .next()
+    .map(|a| a.port())
+    .ok_or_else(|| AppError::UnableToResolveNetAddr(addr.to_owned(), "Host not found".to_owned()))
+}
+
+/// The client end of a control connection, kept open for the whole test.
+pub struct ControlClient {
+  conn: Connection,
+}
+
+impl ControlClient {
+  /// Announce the test to the server at `addr`, and return once it accepted
+  /// and the test should start.
+  pub fn start(addr: &str, params: TestParams) -> Result<Self, AppError> {
+    let socket_addrs: Vec<SocketAddr> = addr
+      .to_socket_addrs()
+      .map_err(|e| AppError::UnableToResolveNetAddr(addr.to_owned(), format!("{}", e)))?
+      .collect();
+    let mut last_err = None;
+    let mut stream = None;
+    for socket_addr in socket_addrs.iter() {
+      match TcpStream::connect_timeout(socket_addr, IO_TIMEOUT) {
+        Ok(s) => {
+          stream = Some(s);
+          break;
+        }
+        Err(e) => last_err = Some(e),
+      }
+    }
+    let stream = match (stream, last_err) {
+      (Some(stream), _) => stream,
+      (None, Some(e)) => return Err(AppError::IOError("connect to control server", e)),
+      (None, None) => {
+        return Err(AppError::UnableToResolveNetAddr(
+          addr.to_owned(),
+          "Host not found".to_owned(),
+        ))
+      }
+    };
+
+    let mut conn = Connection::new(stream)?;
+    let start_delay = Duration::from_millis(params.start_delay_ms);
+    conn.send(&ClientMessage::Hello { params })?;
+    match conn.recv()? {
+      ServerMessage::Accept { backend, mtu } => {
+        eprintln!(
+          "Control: {addr} ({backend}, mtu {mtu}) accepted the test, starting in {start_delay:?}."
+        );
+      }
+      ServerMessage::Reject { reason } => return Err(AppError::ControlRejected(reason)),
+      msg => return Err(AppError::ControlError(format!("expected accept, got {msg:?}"))),
+    }
+    thread::sleep(start_delay);
+    Ok(Self { conn })
+  }
+
+  /// Tell the server that the test is over, and get its counts of the
+  /// packets sent from `ports`.
+  pub fn finish(mut self, ports: &[u16]) -> Result<PeerCounts, AppError> {
+    self.conn.send(&ClientMessage::Done {
+      ports: ports.to_vec(),
+    })?;
+    match self.conn.recv()? {
+      ServerMessage::Report { counts } => Ok(counts),
+      msg => Err(AppError::ControlError(format!("expected report, got {msg:?}"))),
+    }
+  }
+}
+
+/// The counts of an echo server for each client address, so that clients can
+/// be told how many of their packets made it there.
+///
+/// Only clients with an accepted control session are counted, so that other
+/// traffic neither costs anything nor fills up the table.
+#[derive(Default)]
+pub struct PeerTable {
+  peers: Mutex<HashMap<SocketAddr, PeerCounts>>,
+
+  /// Addresses of the clients with an accepted control session.
+  sessions: Mutex<HashMap<IpAddr, ClientSessions>>,
+
+  /// Bumped whenever the set of addresses in `sessions` changes, so that
+  /// shards know to update their copy.
+  sessions_version: AtomicU64,
+}
+
+impl PeerTable {
+  /// Get a buffer for one echo thread to count into.
+  pub fn shard(&self) -> PeerShard<'_> {
+    let mut shard = PeerShard {
+      table: self,
+      counts: HashMap::new(),
+      last_flush: Instant::now(),
+      clients: Vec::new(),
+      clients_version: 0,
+    };
+    shard.update_clients();
+    shard
+  }
+
+  /// Start counting packets from `ip`, until the returned session is dropped.
+  /// If `exclusive`, there can't be another session from `ip` at the same
+  /// time, and None is returned if there is one already.
+  fn open_session(&self, ip: IpAddr, exclusive: bool) -> Option<Session<'_>> {
+    let mut sessions = self.sessions.lock().unwrap();
+    if let Some(client) = sessions.get_mut(&ip) {
+      if exclusive {
+        return None;
+      }
+      client.open += 1;
+    } else {
+      let run = self.sessions_version.fetch_add(1, Ordering::Release) + 1;
+      sessions.insert(ip, ClientSessions { open: 1, run });
+    }
+    Some(Session { table: self, ip })
+  }
+
+  /// Remove the counts of the given ports of `ip`, and return their sum.
+  fn take(&self, ip: IpAddr, ports: &[u16]) -> PeerCounts {
+    let mut peers = self.peers.lock().unwrap();
+    let mut total = PeerCounts::default();
+    for &port in ports {
+      if let Some(counts) = peers.remove(&SocketAddr::new(ip, port)) {
+        total.add(&counts);
+      }
+    }
+    total
+  }
+}
+
+/// The control sessions of one client address.
+struct ClientSessions {
+  open: usize,
+
+  /// Tells apart the runs of a client whose sessions don't overlap.
+  run: u64,
+}
+
+/// A control session, during which packets from its client are counted.
+struct Session<'a> {
+  table: &'a PeerTable,
+  ip: IpAddr,
+}
+
+impl Drop for Session<'_> {
+  /// Stop counting packets from the client once it has no sessions left, and
+  /// forget whatever was not taken.
+  fn drop(&mut self) {
+    let mut sessions = self.table.sessions.lock().unwrap();
+    let client = sessions.get_mut(&self.ip).unwrap();
+    client.open -= 1;
+    if client.open > 0 {
+      return;
+    }
+    sessions.remove(&self.ip);
+    self.table.sessions_version.fetch_add(1, Ordering::Release);
+    let mut peers = self.table.peers.lock().unwrap();
+    peers.retain(|addr, _| addr.ip() != self.ip);
+  }
+}
+
+/// One thread's counts, merged into the [`PeerTable`] every
+/// [`FLUSH_INTERVAL`] so that the lock stays out of the packet path.
+pub struct PeerShard<'a> {
+  table: &'a PeerTable,
+  counts: HashMap<SocketAddr, PeerCounts>,
+  last_flush: Instant,
+
+  /// Copy of the addresses with a control session and their runs, and the
+  /// [`PeerTable::sessions_version`] it was taken at.  There are usually only
+  /// a few, so a list is faster to search than a set.
+  clients: Vec<(IpAddr, u64)>,
+  clients_version: u64,
+}
+
+impl PeerShard<'_> {
+  /// Get the counts for `addr`, if it has a control session.
+  #[inline]
+  fn counts_for(&mut self, addr: SocketAddr) -> Option<&mut PeerCounts> {
+    if self.clients.is_empty() {
+      return None;
+    }
+    let addr = canonical(addr);
+    self.run_of(addr.ip())?;
+    Some(self.counts.entry(addr).or_default())
+  }
+
+  /// Get the run of the control session of `ip`, which changes whenever the
+  /// client starts a new test after the previous one ended, or None if it has
+  /// no session.
+  #[inline]
+  pub fn run_of(&self, ip: IpAddr) -> Option<u64> {
+    let ip = ip.to_canonical();
+    self.clients.iter().find(|(client, _)| *client == ip).map(|&(_, run)| run)
+  }
+
+  /// Count a packet received from `addr`, and whether it was sent back.
+  #[inline]
+  pub fn count_rx(&mut self, addr: SocketAddr, bytes: u64) {
+    if let Some(counts) = self.counts_for(addr) {
+      counts.rx_packets += 1;
+      counts.rx_bytes += bytes;
+    }
+  }
+
+  /// Count a packet echoed back to `addr`, or an error sending it.
+  #[inline]
+  pub fn count_tx(&mut self, addr: SocketAddr, sent: bool) {
+    if let Some(counts) = self.counts_for(addr) {
+      if sent {
+        counts.tx_packets += 1;
+      } else {
+        counts.tx_errors += 1;
+      }
+    }
+  }
+
+  /// Merge the counts into the table if it has been a while, and pick up
+  /// changes to the control sessions.  This should be called regularly, even
+  /// when no packets arrive.
+  pub fn maybe_flush(&mut self) {
+    if self.table.sessions_version.load(Ordering::Acquire) != self.clients_version {
+      self.flush();
+      self.update_clients();
+    } else if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+      self.flush();
+    }
+  }
+
+  fn update_clients(&mut self) {
+    let sessions = self.table.sessions.lock().unwrap();
+    self.clients_version = self.table.sessions_version.load(Ordering::Acquire);
+    self.clients = sessions.iter().map(|(&ip, client)| (ip, client.run)).collect();
+  }
+
+  fn flush(&mut self) {
+    self.last_flush = Instant::now();
+    if self.counts.is_empty() {
+      return;
+    }
+    // Counts of clients whose sessions ended since would never be taken.
+    let sessions = self.table.sessions.lock().unwrap();
+    let mut peers = self.table.peers.lock().unwrap();
+    for (addr, counts) in self.counts.drain() {
+      if sessions.contains_key(&addr.ip()) {
+        peers.entry(addr).or_default().add(&counts);
+      }
+    }
+  }
+}
+
+impl Drop for PeerShard<'_> {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+/// Addresses as seen by both an IPv4 and a dual-stack IPv6 socket.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// What a server can do, to check test parameters against.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+  pub backend: &'static str,
+  pub mode: TestMode,
+  pub data_port: u16,
+  pub mtu: usize,
+  pub packet_format: PacketFormat,
+}
+
+impl ServerInfo {
+  /// Check whether the test can be run against this server, and if not, why.
+  fn check(&self, params: &TestParams) -> Result<(), String> {
+    if params.version != PROTOCOL_VERSION {
+      return Err(format!(
+        "protocol version {} is not supported, the server uses version {}",
+        params.version, PROTOCOL_VERSION
+      ));
+    }
+    if params.mode != self.mode {
+      return Err(format!(
+        "{:?} tests are not supported by {}",
+        params.mode, self.backend
+      ));
+    }
+    if params.data_port != self.data_port {
+      return Err(format!(
+        "the server listens for packets on port {}, not {}",
+        self.data_port, params.data_port
+      ));
+    }
+    if params.mode == TestMode::Sink && params.packet_format == PacketFormat::Twamp {
+      return Err("sinks don't support TWAMP packets".to_owned());
+    }
+    if params.packet_format != self.packet_format {
+      return Err(format!(
+        "the server expects {:?} packets, not {:?}",
+        self.packet_format, params.packet_format
+      ));
+    }
+    if params.packet_size as usize > self.mtu {
+      return Err(format!(
+        "packets of {} bytes are larger than the server's mtu of {}",
+        params.packet_size, self.mtu
+      ));
+    }
+    Ok(())
+  }
+}
+
+/// Handle one control connection until the test is over.
+fn serve_client(
+  stream: TcpStream,
+  peer: SocketAddr,
+  info: &ServerInfo,
+  peers: &PeerTable,
+  stop: &AtomicBool,
+) -> Result<(), AppError> {
+  let mut conn = Connection::new(stream)?;
+  let params = match conn.recv()? {
+    ClientMessage::Hello { params } => params,
+    msg => return Err(AppError::ControlError(format!("expected hello, got {msg:?}"))),
+  };
+  if let Err(reason) = info.check(&params) {
+    eprintln!("Control: rejected test from {peer}: {reason}.");
+    return conn.send(&ServerMessage::Reject { reason });
+  }
+  // Count the client's packets from before it hears back, until we're done.
+  // A sink only tells tests apart by the address they come from, so it can
+  // only run one test from each address at a time.
+  let exclusive = info.mode == TestMode::Sink;
+  let Some(_session) = peers.open_session(peer.ip().to_canonical(), exclusive) else {
+    let reason = format!("a test from {} is already running", peer.ip());
+    eprintln!("Control: rejected test from {peer}: {reason}.");
+    return conn.send(&ServerMessage::Reject { reason });
+  };
+  eprintln!(
+    "Control: accepted test from {peer}: {} sockets, {} byte packets, seed {:#x}, starting in {}ms.",
+    params.nb_sockets, params.packet_size, params.seed, params.start_delay_ms
+  );
+  conn.send(&ServerMessage::Accept {
+    backend: info.backend.to_owned(),
+    mtu: info.mtu,
+  })?;
+
+  // The test may run for a long time, so wait for its end in small steps.
+  conn
+    .stream
+    .set_read_timeout(Some(POLL_INTERVAL))
+    .map_err(|e| AppError::IOError("control connection", e))?;
+  let ports = loop {
+    if stop.load(Ordering::Relaxed) {
+      eprintln!("Control: test from {peer} interrupted.");
+      return Ok(());
+    }
+    match conn.try_recv::<ClientMessage>() {
+      Ok(Some(ClientMessage::Done { ports })) => break ports,
+      Ok(None) => {
+        eprintln!("Control: test from {peer} ended without done.");
+        return Ok(());
+      }
+      Ok(Some(msg)) => {
+        return Err(AppError::ControlError(format!("expected done, got {msg:?}")));
+      }
+      Err(e) if is_timeout(&e) => continue,
+      Err(e) => return Err(AppError::IOError("control connection", e)),
+    }
+  };
+
+  // Let the echo threads flush the counts of the last packets.
+  thread::sleep(2 * FLUSH_INTERVAL + POLL_INTERVAL);
+  let counts = peers.take(peer.ip().to_canonical(), &ports);
+  eprintln!(
+    "Control: test from {peer} finished, received {} packets and echoed {}.",
+    counts.rx_packets, counts.tx_packets
+  );
+  conn
+    .stream
+    .set_read_timeout(Some(IO_TIMEOUT))
+    .map_err(|e| AppError::IOError("control connection", e))?;
+  conn.send(&ServerMessage::Report { counts })
+}
+
+/// The server end, accepting control connections next to the data sockets.
+pub struct ControlServer {
+  listener: TcpListener,
+  info: ServerInfo,
+  peers: PeerTable,
+}
+
+impl ControlServer {
+  pub fn bind(addr: &str, info: ServerInfo) -> Result<Self, AppError> {
+    let listener = TcpListener::bind(addr).map_err(|e| AppError::IOError("bind control", e))?;
+    listener
+      .set_nonblocking(true)
+      .map_err(|e| AppError::IOError("bind control", e))?;
+    eprintln!("Control: listening on {addr}.");
+    Ok(Self {
+      listener,
+      info,
+      peers: PeerTable::default(),
+    })
+  }
+
+  /// The table the echo threads count packets into.
+  pub fn peers(&self) -> &PeerTable {
+    &self.peers
+  }
+
+  /// Serve control connections until `stop` is set.
+  pub fn run(&self, stop: &AtomicBool) -> Result<(), AppError> {
+    let (info, peers) = (&self.info, &self.peers);
+    thread::scope(|scope| {
+      while !stop.load(Ordering::Relaxed) {
+        match self.listener.accept() {
+          Ok((stream, peer)) => {
+            scope.spawn(move || {
+              let res = stream
+                .set_nonblocking(false)
+                .map_err(|e| AppError::IOError("control connection", e))
+                .and_then(|()| serve_client(stream, peer, info, peers, stop));
+              if let Err(e) = res {
+                eprintln!("Control: connection from {peer} failed: {e}");
+              }
+            });
+          }
+          Err(e) if is_timeout(&e) => thread::sleep(POLL_INTERVAL),
+          Err(e) => return Err(AppError::IOError("accept control", e)),
+        }
+      }
+      Ok(())
+    })
+  }
+}
@@ -19,4 +19,31 @@ pub enum AppError {
   IoUringError(#[source] io::Error),
   #[error("io_uring: queue full while pushing {0}({1})")]
   IoUringFull(&'static str, usize),
+  #[error("CPU {0} is not available to this process.")]
+  CpuNotAvailable(usize),
+  #[error("{0}:{1}: {2}")]
+  StatsParseError(String, usize, String),
+  #[error("{0}: no stats found.")]
+  EmptyStatsFile(String),
+  #[error("{0}: {1}")]
+  ConfigError(String, String),
+  #[error("Control connection: {0}")]
+  ControlError(String),
+  #[error("The server rejected the test: {0}.")]
+  ControlRejected(String),
+  #[error("{0} regression threshold(s) exceeded.")]
+  ThresholdsExceeded(usize),
+}
+
+impl AppError {
+  /// Get the errno behind this error, if it came from a failed syscall.
+  pub fn raw_os_error(&self) -> Option<i32> {
+    match self {
+      AppError::IOError(_, e) | AppError::StatsFileError(e) | AppError::IoUringError(e) => {
+        e.raw_os_error()
+      }
+      AppError::PacketSizeTooLarge => Some(libc::EMSGSIZE),
+      _ => None,
+    }
+  }
 }
@@ -1,7 +1,11 @@
 //! Some utility functions shared between implementations, like setting up
 //! socket.
 
-use std::{io, net::ToSocketAddrs, time::Duration};
+use std::{
+  io,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+  time::Duration,
+};
 
 use crate::errors::AppError;
 use std::mem;
@@ -51,7 +55,54 @@ pub fn get_sockaddr(addr: &str) -> Result<GetSockaddrRes, AppError> {
   }
 }
 
+/// How long blocking receives wait before returning `EAGAIN`, so that receive
+/// loops can notice a shutdown request.
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Set an integer socket option.
+unsafe fn set_int_sockopt(
+  sock_fd: libc::c_int,
+  level: libc::c_int,
+  name: libc::c_int,
+  val: libc::c_int,
+) -> Result<(), AppError> {
+  set_sockopt(sock_fd, level, name, val)
+}
+
+/// Set a socket option to a plain value of any type.
+unsafe fn set_sockopt<T>(
+  sock_fd: libc::c_int,
+  level: libc::c_int,
+  name: libc::c_int,
+  val: T,
+) -> Result<(), AppError> {
+  unsafe {
+    if libc::setsockopt(
+      sock_fd,
+      level,
+      name,
+      &val as *const _ as *const libc::c_void,
+      mem::size_of_val(&val) as libc::socklen_t,
+    ) == -1
+    {
+      return Err(AppError::IOError("setsockopt", io::Error::last_os_error()));
+    }
+  }
+  Ok(())
+}
+
+unsafe fn set_recv_timeout(sock_fd: libc::c_int) -> Result<(), AppError> {
+  let tv = libc::timeval {
+    tv_sec: RECV_TIMEOUT.as_secs() as _,
+    tv_usec: RECV_TIMEOUT.subsec_micros() as _,
+  };
+  set_sockopt(sock_fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, tv)
+}
+
 /// Connect a UDP socket to the given address, and return the socket fd.
+///
+/// The socket will have `IP_RECVERR` (or `IPV6_RECVERR`) enabled, so ICMP
+/// errors should be read with [`super::errqueue::drain_error_queue`].
 pub fn setup_send_socket(dest_addr: &GetSockaddrRes) -> Result<libc::c_int, AppError> {
   let (af, ref sock_addr, addr_len) = *dest_addr;
   let sock_fd = unsafe { libc::socket(af, libc::SOCK_DGRAM, 0) };
@@ -59,6 +110,14 @@ pub fn setup_send_socket(dest_addr: &GetSockaddrRes) -> Result<libc::c_int, AppE
     return Err(AppError::IOError("socket", io::Error::last_os_error()));
   }
   unsafe {
+    // We also receive the echoed packets on this socket.
+    set_int_sockopt(sock_fd, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, 1)?;
+    set_recv_timeout(sock_fd)?;
+    if af == libc::AF_INET6 {
+      set_int_sockopt(sock_fd, libc::SOL_IPV6, libc::IPV6_RECVERR, 1)?;
+    } else {
+      set_int_sockopt(sock_fd, libc::SOL_IP, libc::IP_RECVERR, 1)?;
+    }
     while libc::connect(sock_fd, sock_addr, addr_len) == -1 {
       let errno = *libc::__errno_location();
       if errno == libc::EAGAIN {
@@ -78,18 +137,10 @@ pub fn setup_recv_socket(listen_addr: &GetSockaddrRes) -> Result<libc::c_int, Ap
   if sock_fd == -1 {
     return Err(AppError::IOError("socket", io::Error::last_os_error()));
   }
-  let val: libc::c_int = 1;
   unsafe {
-    if libc::setsockopt(
-      sock_fd,
-      libc::SOL_SOCKET,
-      libc::SO_REUSEPORT,
-      &val as *const _ as *const libc::c_void,
-      mem::size_of_val(&val) as libc::socklen_t,
-    ) == -1
-    {
-      return Err(AppError::IOError("setsockopt", io::Error::last_os_error()));
-    }
+    set_int_sockopt(sock_fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    set_int_sockopt(sock_fd, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, 1)?;
+    set_recv_timeout(sock_fd)?;
     if libc::bind(sock_fd, sock_addr, addr_len) == -1 {
       return Err(AppError::IOError("bind", io::Error::last_os_error()));
     }
@@ -97,6 +148,42 @@ pub fn setup_recv_socket(listen_addr: &GetSockaddrRes) -> Result<libc::c_int, Ap
   Ok(sock_fd)
 }
 
+/// Report the TTL (or IPv6 hop limit) of received packets, see
+/// [`super::sys::get_recv_ttl`].
+pub fn enable_recv_ttl(sock_fd: libc::c_int, af: libc::c_int) -> Result<(), AppError> {
+  unsafe {
+    if af == libc::AF_INET6 {
+      set_int_sockopt(sock_fd, libc::SOL_IPV6, libc::IPV6_RECVHOPLIMIT, 1)
+    } else {
+      set_int_sockopt(sock_fd, libc::SOL_IP, libc::IP_RECVTTL, 1)
+    }
+  }
+}
+
+/// Set the TTL (or IPv6 hop limit) of the packets we send.
+pub fn set_ttl(sock_fd: libc::c_int, af: libc::c_int, ttl: u8) -> Result<(), AppError> {
+  unsafe {
+    if af == libc::AF_INET6 {
+      set_int_sockopt(sock_fd, libc::SOL_IPV6, libc::IPV6_UNICAST_HOPS, ttl as libc::c_int)
+    } else {
+      set_int_sockopt(sock_fd, libc::SOL_IP, libc::IP_TTL, ttl as libc::c_int)
+    }
+  }
+}
+
+/// Estimate the number of bytes a UDP packet with the given payload length
+/// takes up on an Ethernet link, including UDP and IP headers, Ethernet header
+/// and FCS, minimum frame padding, and the preamble and inter-frame gap.
+pub fn wire_size(af: libc::c_int, payload_len: usize) -> u64 {
+  const UDP_HEADER: usize = 8;
+  const ETH_HEADER_AND_FCS: usize = 14 + 4;
+  const ETH_MIN_FRAME: usize = 64;
+  const ETH_PREAMBLE_AND_IFG: usize = 8 + 12;
+  let ip_header = if af == libc::AF_INET6 { 40 } else { 20 };
+  let frame = (payload_len + UDP_HEADER + ip_header + ETH_HEADER_AND_FCS).max(ETH_MIN_FRAME);
+  (frame + ETH_PREAMBLE_AND_IFG) as u64
+}
+
 /// Get the local port used by the socket.
 pub unsafe fn get_socket_local_port(fd: libc::c_int) -> Result<libc::in_port_t, AppError> {
   // We can use sockaddr_in here since the only possibility is in or in6, and
@@ -107,5 +194,43 @@ pub unsafe fn get_socket_local_port(fd: libc::c_int) -> Result<libc::in_port_t,
   if res == -1 {
     return Err(AppError::IOError("getsockname", io::Error::last_os_error()));
   }
-  Ok(addr.sin_port)
+  Ok(libc::in_port_t::from_be(addr.sin_port))
+}
+
+/// Convert the source address of a received packet, or `None` if it is not an
+/// IP address.
+pub fn std_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+  match addr.ss_family as libc::c_int {
+    libc::AF_INET => {
+      let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+      let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+      Some(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+    }
+    libc::AF_INET6 => {
+      let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+      let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+      Some(SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port)))
+    }
+    _ => None,
+  }
+}
+
+/// Turns the cumulative `SO_RXQ_OVFL` counter of a socket into the number of
+/// packets dropped since the last time we saw it.
+#[derive(Debug, Default)]
+pub struct RxqOvflTracker {
+  last: u32,
+}
+
+impl RxqOvflTracker {
+  pub fn update(&mut self, counter: Option<u32>) -> u64 {
+    match counter {
+      Some(counter) => {
+        let delta = counter.wrapping_sub(self.last);
+        self.last = counter;
+        delta as u64
+      }
+      None => 0,
+    }
+  }
 }
@@ -0,0 +1,145 @@
+//! Reading ICMP errors from the socket error queue.
+//!
+//! With `IP_RECVERR` / `IPV6_RECVERR` enabled on a socket, the kernel queues
+//! the details of every ICMP error it receives for that socket, instead of
+//! only reporting a single pending errno on the next call. This allows us to
+//! tell apart the echo server being down (port unreachable), packets being
+//! too large for the path, and routing loops.
+
+use std::{
+  io, mem,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{errors::AppError, io_impl::sys::CmsgBuf, stats::Stats};
+
+/// Counts of ICMP errors read from the error queue.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IcmpErrors {
+  pub port_unreachable: u64,
+  pub frag_needed: u64,
+  pub ttl_exceeded: u64,
+  pub other: u64,
+
+  /// The most recent MTU reported by a fragmentation needed / packet too big
+  /// error.
+  pub reported_mtu: Option<u32>,
+}
+
+impl IcmpErrors {
+  pub fn is_empty(&self) -> bool {
+    self.port_unreachable == 0 && self.frag_needed == 0 && self.ttl_exceeded == 0 && self.other == 0
+  }
+
+  fn add(&mut self, ee: &libc::sock_extended_err) {
+    // ICMP: 3 = destination unreachable, 11 = time exceeded.
+    // ICMPv6: 1 = destination unreachable, 2 = packet too big, 3 = time exceeded.
+    match (ee.ee_origin, ee.ee_type, ee.ee_code) {
+      (libc::SO_EE_ORIGIN_ICMP, 3, 3) | (libc::SO_EE_ORIGIN_ICMP6, 1, 4) => {
+        self.port_unreachable += 1
+      }
+      (libc::SO_EE_ORIGIN_ICMP, 3, 4) | (libc::SO_EE_ORIGIN_ICMP6, 2, _) => {
+        self.frag_needed += 1;
+        self.reported_mtu = Some(ee.ee_info);
+      }
+      (libc::SO_EE_ORIGIN_ICMP, 11, _) | (libc::SO_EE_ORIGIN_ICMP6, 3, _) => self.ttl_exceeded += 1,
+      _ => self.other += 1,
+    }
+  }
+
+  /// Add these counts to the statistics for a step.
+  pub fn record(&self, stats: &Stats) {
+    stats.icmp_port_unreachable.fetch_add(self.port_unreachable, Ordering::Relaxed);
+    stats.icmp_frag_needed.fetch_add(self.frag_needed, Ordering::Relaxed);
+    stats.icmp_ttl_exceeded.fetch_add(self.ttl_exceeded, Ordering::Relaxed);
+    stats.icmp_other.fetch_add(self.other, Ordering::Relaxed);
+    if let Some(mtu) = self.reported_mtu {
+      stats.icmp_reported_mtu.store(mtu as u64, Ordering::Relaxed);
+    }
+  }
+}
+
+/// Read and count everything currently in the error queue of the socket.
+pub fn drain_error_queue(sock_fd: libc::c_int) -> Result<IcmpErrors, AppError> {
+  let mut errors = IcmpErrors::default();
+  loop {
+    // We don't care about the packet that caused the error, so let it be
+    // truncated.
+    let mut data_buf = [0u8; 1];
+    let mut iov = libc::iovec {
+      iov_base: data_buf.as_mut_ptr() as *mut _,
+      iov_len: data_buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = Default::default();
+    unsafe {
+      let mut msg: libc::msghdr = mem::zeroed();
+      msg.msg_iov = &mut iov;
+      msg.msg_iovlen = 1;
+      msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+      msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+      let ret = libc::recvmsg(sock_fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT);
+      if ret == -1 {
+        let errno = *libc::__errno_location();
+        if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
+          return Ok(errors);
+        }
+        return Err(AppError::IOError("recvmsg(MSG_ERRQUEUE)", io::Error::last_os_error()));
+      }
+      let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+      while !cmsg.is_null() {
+        let level = (*cmsg).cmsg_level;
+        let ty = (*cmsg).cmsg_type;
+        if (level == libc::SOL_IP && ty == libc::IP_RECVERR)
+          || (level == libc::SOL_IPV6 && ty == libc::IPV6_RECVERR)
+        {
+          let ee = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err);
+          errors.add(&ee);
+        }
+        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+      }
+    }
+  }
+}
+
+/// Totals of ICMP errors across the whole run, to be printed at exit.
+#[derive(Debug, Default)]
+pub struct IcmpErrorSummary {
+  port_unreachable: AtomicU64,
+  frag_needed: AtomicU64,
+  ttl_exceeded: AtomicU64,
+  other: AtomicU64,
+  reported_mtu: AtomicU64,
+}
+
+impl IcmpErrorSummary {
+  pub fn add(&self, errors: &IcmpErrors) {
+    self.port_unreachable.fetch_add(errors.port_unreachable, Ordering::Relaxed);
+    self.frag_needed.fetch_add(errors.frag_needed, Ordering::Relaxed);
+    self.ttl_exceeded.fetch_add(errors.ttl_exceeded, Ordering::Relaxed);
+    self.other.fetch_add(errors.other, Ordering::Relaxed);
+    if let Some(mtu) = errors.reported_mtu {
+      self.reported_mtu.store(mtu as u64, Ordering::Relaxed);
+    }
+  }
+
+  pub fn print(&self) {
+    let port_unreachable = self.port_unreachable.load(Ordering::Relaxed);
+    let frag_needed = self.frag_needed.load(Ordering::Relaxed);
+    let ttl_exceeded = self.ttl_exceeded.load(Ordering::Relaxed);
+    let other = self.other.load(Ordering::Relaxed);
+    if port_unreachable + frag_needed + ttl_exceeded + other == 0 {
+      eprintln!("No ICMP errors received.");
+      return;
+    }
+    eprintln!("ICMP errors received:");
+    eprintln!("  port unreachable: {port_unreachable}");
+    if frag_needed > 0 {
+      let mtu = self.reported_mtu.load(Ordering::Relaxed);
+      eprintln!("  fragmentation needed: {frag_needed} (last reported MTU: {mtu})");
+    } else {
+      eprintln!("  fragmentation needed: 0");
+    }
+    eprintln!("  TTL exceeded: {ttl_exceeded}");
+    eprintln!("  other: {other}");
+  }
+}
@@ -20,7 +20,7 @@
 
 use std::{
   collections::HashMap,
-  io,
+  io, mem,
   sync::atomic::Ordering,
   time::{Duration, Instant},
 };
@@ -28,9 +28,16 @@ use std::{
 use io_uring::IoUring;
 
 use crate::{
+  control::{PeerShard, PeerTable},
   errors::AppError,
-  io_impl::common::{get_sockaddr, setup_recv_socket},
-  stats::{get_time_value_now, StatsAggregator},
+  io_impl::common::{
+    enable_recv_ttl, get_sockaddr, get_socket_local_port, setup_recv_socket, std_socket_addr,
+    wire_size, RxqOvflTracker,
+  },
+  io_impl::sys::{get_recv_ttl, get_rxq_ovfl, CmsgBuf},
+  pkt::{clock_now, ReflectMode, Reflector},
+  shutdown::Shutdown,
+  stats::{get_time_value_now, StatsAggregator, StatsShard},
 };
 
 /// The main entry point for the iouring echo server.
@@ -41,6 +48,12 @@ use crate::{
 /// Note that to simplify implementation, we will only use 1 user-mode thread,
 /// even when kernel polling is not used. When kernel polling is used, this is
 /// likely the most CPU-efficient approach.
+///
+/// If `peers` is given, packets are also counted per client address, to be
+/// reported over the control connection.  `reflect` says what to do to the
+/// packets before sending them back.  Reflector timestamps are taken when we
+/// see the receive complete and when we queue the send, since we don't see
+/// when either actually happens.
 pub fn iouring_echo(
   listen_addr: &str,
   mtu: usize,
@@ -50,17 +63,27 @@ pub fn iouring_echo(
   ring_size: u32,
   nb_recv: u32,
   sqpoll_idle: u32,
+  reflect: ReflectMode,
+  peers: Option<&PeerTable>,
+  shutdown: Shutdown,
 ) -> Result<(), AppError> {
   assert!(ring_size > 0 && ring_size.is_power_of_two());
   assert!(nb_recv <= ring_size);
   let resolved_addr = get_sockaddr(listen_addr)?;
 
   let mut socks = Vec::with_capacity(nb_sockets);
-  for _ in 0..nb_sockets {
+  let mut shards = Vec::with_capacity(nb_sockets);
+  for i in 0..nb_sockets {
     let sock_fd = setup_recv_socket(&resolved_addr)?;
+    if reflect == ReflectMode::Twamp {
+      enable_recv_ttl(sock_fd, resolved_addr.0)?;
+    }
+    stats.set_socket_local_port(i, unsafe { get_socket_local_port(sock_fd) }?);
+    shards.push(stats.socket_shard(i));
     let ring = build_ring(ring_size, sqpoll_idle, sock_fd).map_err(AppError::IoUringError)?;
     let ring_size = ring_size as usize;
-    let sock_struct = Socket::new(ring, ring_size, sock_fd, mtu);
+    let mut sock_struct = Socket::new(ring, ring_size, sock_fd, resolved_addr.0, mtu);
+    sock_struct.reflector = Reflector::new(reflect);
     socks.push(sock_struct);
     let sock_struct = socks.last_mut().unwrap();
 
@@ -78,13 +101,36 @@ pub fn iouring_echo(
     }
   }
 
+  let mut peer_shard = peers.map(|p| p.shard());
   let mut last_recv_report = Instant::now();
+  let mut drain = shutdown.drain_timer();
+  let mut stop_deadline = None;
 
   loop {
+    if stop_deadline.is_none() && drain.done() {
+      for sock in socks.iter_mut() {
+        sock.stop();
+      }
+      stop_deadline = Some(Instant::now() + Duration::from_secs(1));
+    }
+    if let Some(deadline) = stop_deadline {
+      if socks.iter().all(|s| s.is_idle()) {
+        return Ok(());
+      }
+      if Instant::now() > deadline {
+        eprintln!("Warn: some io_uring requests did not complete after shutdown.");
+        // The kernel may still write into the buffers, so don't free them.
+        mem::forget(socks);
+        return Ok(());
+      }
+    }
+    if let Some(peer_shard) = &mut peer_shard {
+      peer_shard.maybe_flush();
+    }
     for i in 0..nb_sockets {
       let sock = &mut socks[i];
       let initial_cql = sock.ring.completion().len();
-      if let Err(e) = sock.check_cq(stats, start_time) {
+      if let Err(e) = sock.check_cq(&mut shards[i], peer_shard.as_mut(), start_time) {
         eprintln!("Error encountered in socket {i}: {e}");
       }
       let now_cql = sock.ring.completion().len();
@@ -102,7 +148,7 @@ pub fn iouring_echo(
           .map_err(AppError::IoUringError)?;
       }
       let now = Instant::now();
-      if sock.nb_active_recv + 2 < nb_recv as usize {
+      if sock.nb_active_recv + 2 < nb_recv as usize && !sock.stopping {
         if now - last_recv_report > std::time::Duration::from_secs(5) {
           last_recv_report += Duration::from_secs(1); // Report again in 1 second.
           eprintln!(
@@ -120,18 +166,28 @@ pub fn iouring_echo(
 struct Socket {
   sock_fd: libc::c_int,
   ring: IoUring,
+  af: libc::c_int,
   mtu: usize,
 
   // We use box here to prevent accidentally moving the buffers.
   msghdr_buf: Box<[libc::msghdr]>,
   iovec_buf: Box<[libc::iovec]>,
   sockaddr_buf: Box<[libc::sockaddr_storage]>,
+  cmsg_buf: Box<[CmsgBuf]>,
 
   /// A buffer containing mtu * ring_size bytes to store all the packet data.
   pkt_data_buf: Box<[u8]>,
 
   state_buf: Box<[PacketSlotState]>,
   nb_active_recv: usize,
+  nb_active_send: usize,
+
+  /// Set on shutdown, after which we stop submitting new recv requests.
+  stopping: bool,
+
+  reflector: Reflector,
+
+  rxq_ovfl: RxqOvflTracker,
 
   // For debugging
   debug: bool,
@@ -162,19 +218,25 @@ fn build_ring(
 }
 
 impl Socket {
-  fn new(ring: IoUring, ring_size: usize, sock_fd: libc::c_int, mtu: usize) -> Self {
+  fn new(ring: IoUring, ring_size: usize, sock_fd: libc::c_int, af: libc::c_int, mtu: usize) -> Self {
     let mut sock = unsafe {
       Socket {
         ring,
         sock_fd,
+        af,
         mtu,
         msghdr_buf: Box::new_zeroed_slice(ring_size).assume_init(),
         iovec_buf: Box::new_zeroed_slice(ring_size).assume_init(),
         sockaddr_buf: Box::new_zeroed_slice(ring_size).assume_init(),
+        cmsg_buf: Box::new_zeroed_slice(ring_size).assume_init(),
         pkt_data_buf: Box::new_zeroed_slice(ring_size * mtu).assume_init(),
         // assume_init is safe since the enum is repr(C) and 0 is what we want.
         state_buf: Box::new_zeroed_slice(ring_size).assume_init(),
         nb_active_recv: 0,
+        nb_active_send: 0,
+        stopping: false,
+        reflector: Reflector::new(ReflectMode::Unchanged),
+        rxq_ovfl: RxqOvflTracker::default(),
         debug: false,
         request_tags: HashMap::new(),
         next_request_tag: 0,
@@ -269,8 +331,8 @@ impl Socket {
       msg_namelen: std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
       msg_iov: &mut self.iovec_buf[index] as *mut _ as *mut _,
       msg_iovlen: 1,
-      msg_control: std::ptr::null_mut(),
-      msg_controllen: 0,
+      msg_control: &mut self.cmsg_buf[index] as *mut _ as *mut _,
+      msg_controllen: std::mem::size_of::<CmsgBuf>(),
       msg_flags: 0,
     };
 
@@ -299,11 +361,30 @@ impl Socket {
     }
     // dbg!(("send", index));
     self.state_buf[index] = PacketSlotState::SendInProgress;
+    self.nb_active_send += 1;
     Ok(())
   }
 
+  /// Stop receiving.  In-flight recv requests will complete with no data.
+  fn stop(&mut self) {
+    self.stopping = true;
+    // This returns ENOTCONN for unconnected UDP sockets, but still wakes up
+    // pending receives.
+    unsafe { libc::shutdown(self.sock_fd, libc::SHUT_RD) };
+  }
+
+  /// Whether there are no requests in flight.
+  fn is_idle(&self) -> bool {
+    self.nb_active_recv == 0 && self.nb_active_send == 0
+  }
+
   /// Consume and handle all new entries in the completion queue.
-  fn check_cq(&mut self, stats: &StatsAggregator, start_time: Instant) -> Result<(), AppError> {
+  fn check_cq(
+    &mut self,
+    stats: &mut StatsShard,
+    mut peers: Option<&mut PeerShard>,
+    start_time: Instant,
+  ) -> Result<(), AppError> {
     // To work around lifetime issues, we can't keep the ring or its queues
     // borrowed, but re-borrowing it is free anyway.
 
@@ -328,25 +409,63 @@ impl Socket {
         PacketSlotState::RecvInProgress => {
           self.nb_active_recv -= 1;
           if entry.result() <= 0 {
-            // Recv failed (or no packets), ignore and retry.
-            self.push_recv(index)?;
+            // Recv failed (or no packets), ignore and retry, unless we are
+            // stopping.
+            if !self.stopping {
+              self.push_recv(index)?;
+            }
           } else {
             // Recv completed and we have the packet now, so send it straight
             // back.  But we need to update the iovec with the actual message
             // length.
-            self.iovec_buf[index].iov_len = usize::try_from(entry.result()).unwrap();
+            let recv_size = usize::try_from(entry.result()).unwrap();
+            let recv_clock = if self.reflector.needs_clock() { clock_now() } else { 0 };
+            let ttl = unsafe { get_recv_ttl(&self.msghdr_buf[index]) };
+            let start = index * self.mtu;
+            let send_size = self.reflector.reflect(
+              &mut self.pkt_data_buf[start..start + self.mtu],
+              recv_size,
+              recv_clock,
+              ttl,
+            );
+            self.iovec_buf[index].iov_len = send_size;
+            let recv_wire_size = wire_size(self.af, recv_size);
+            let sock_drops = self
+              .rxq_ovfl
+              .update(unsafe { get_rxq_ovfl(&self.msghdr_buf[index]) });
             self.push_send(index)?;
+            if let (Some(peers), Some(src_addr)) =
+              (&mut peers, std_socket_addr(&self.sockaddr_buf[index]))
+            {
+              peers.count_rx(src_addr, recv_size as u64);
+            }
             stats.access_step(get_time_value_now(start_time), |stats| {
-              stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+              stats.count_rx(1, recv_size as u64, recv_wire_size);
+              stats.rx_sock_drops.fetch_add(sock_drops, Ordering::Relaxed);
             });
           }
         }
         PacketSlotState::SendInProgress => {
           // Send completed (or failed), so we can go back to recv now for the next packet.
+          self.nb_active_send -= 1;
+          let result = entry.result();
+          let af = self.af;
+          if let (Some(peers), Some(dest_addr)) =
+            (&mut peers, std_socket_addr(&self.sockaddr_buf[index]))
+          {
+            peers.count_tx(dest_addr, result >= 0);
+          }
           stats.access_step(get_time_value_now(start_time), |stats| {
-            stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+            if result >= 0 {
+              let sent = result as usize;
+              stats.count_tx(1, sent as u64, wire_size(af, sent));
+            } else {
+              stats.count_send_errors(Some(-result), 1);
+            }
           });
-          self.push_recv(index)?;
+          if !self.stopping {
+            self.push_recv(index)?;
+          }
         }
       }
     }
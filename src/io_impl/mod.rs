@@ -2,8 +2,11 @@
 //! sending/receiving.
 
 mod common;
+mod errqueue;
 mod sys;
+mod window;
 pub mod syscall_sendrecv;
 pub mod syscall_echo;
+pub mod syscall_sink;
 pub mod iouring_sendrecv;
 pub mod iouring_echo;
@@ -19,8 +19,10 @@ pub unsafe fn send(sock_fd: libc::c_int, packet_data: &[u8]) -> Result<(), AppEr
       }
       return Err(AppError::IOError("send", io::Error::last_os_error()));
     }
+    // Not `as _`, since serde_json's `PartialEq` impls for `isize` make it
+    // ambiguous.
     #[cfg(debug_assertions)]
-    if ret != packet_data.len() as _ {
+    if ret != packet_data.len() as isize {
       unreachable!("Did not send the full packet...?");
       // There is no "partial write" for UDP - if the message is larger than
       // the max length allowable it will return EMSGSIZE.
@@ -29,7 +31,16 @@ pub unsafe fn send(sock_fd: libc::c_int, packet_data: &[u8]) -> Result<(), AppEr
   }
 }
 
-pub unsafe fn sendmmsg(sock_fd: libc::c_int, pkts: &mut [libc::mmsghdr]) -> Result<(), AppError> {
+/// Send all the given packets, retrying until either everything is sent or an
+/// error occurs.
+///
+/// Returns the number of packets the kernel accepted, and the error that
+/// stopped us from sending the rest, if any.
+pub unsafe fn sendmmsg(
+  sock_fd: libc::c_int,
+  pkts: &mut [libc::mmsghdr],
+) -> (usize, Option<AppError>) {
+  let mut sent = 0usize;
   let mut rest = &mut pkts[..];
   while !rest.is_empty() {
     unsafe {
@@ -42,33 +53,94 @@ pub unsafe fn sendmmsg(sock_fd: libc::c_int, pkts: &mut [libc::mmsghdr]) -> Resu
       if ret == -1 {
         let errno = *libc::__errno_location();
         if errno == libc::EMSGSIZE {
-          return Err(AppError::PacketSizeTooLarge);
+          return (sent, Some(AppError::PacketSizeTooLarge));
         }
-        return Err(AppError::IOError("sendmmsg", io::Error::last_os_error()));
+        return (
+          sent,
+          Some(AppError::IOError("sendmmsg", io::Error::last_os_error())),
+        );
       }
-      rest = &mut rest[usize::try_from(ret).unwrap()..];
+      let ret = usize::try_from(ret).unwrap();
+      sent += ret;
+      rest = &mut rest[ret..];
     }
   }
-  Ok(())
+  (sent, None)
 }
 
-pub unsafe fn recv(sock_fd: libc::c_int, recv_buf: &mut [u8]) -> Result<usize, AppError> {
+/// A buffer for ancillary data, aligned for `cmsghdr` and large enough for the
+/// control messages we ask for.
+pub type CmsgBuf = [u64; 8];
+
+/// Find the `SO_RXQ_OVFL` drop counter in the control messages of a received
+/// message.  The kernel only includes it once the counter is non-zero.
+pub unsafe fn get_rxq_ovfl(msg: &libc::msghdr) -> Option<u32> {
   unsafe {
-    let ret = libc::recv(
-      sock_fd,
-      recv_buf.as_mut_ptr() as *mut _,
-      recv_buf.len(),
-      libc::MSG_TRUNC,
-    );
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SO_RXQ_OVFL {
+        return Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32));
+      }
+      cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+  }
+}
+
+/// Find the TTL (or IPv6 hop limit) a message arrived with in its control
+/// messages, which are only there with `IP_RECVTTL` or `IPV6_RECVHOPLIMIT`.
+pub unsafe fn get_recv_ttl(msg: &libc::msghdr) -> Option<u8> {
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+      let (level, ty) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+      if (level == libc::SOL_IP && ty == libc::IP_TTL)
+        || (level == libc::SOL_IPV6 && ty == libc::IPV6_HOPLIMIT)
+      {
+        let ttl = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+        return Some(ttl as u8);
+      }
+      cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+  }
+}
+
+pub struct RecvRes {
+  pub recv_size: usize,
+
+  /// The socket's `SO_RXQ_OVFL` drop counter, if reported.
+  pub rxq_ovfl: Option<u32>,
+}
+
+pub unsafe fn recv(sock_fd: libc::c_int, recv_buf: &mut [u8]) -> Result<RecvRes, AppError> {
+  unsafe {
+    let mut iov = libc::iovec {
+      iov_base: recv_buf.as_mut_ptr() as *mut _,
+      iov_len: recv_buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = Default::default();
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+    let ret = libc::recvmsg(sock_fd, &mut msg, libc::MSG_TRUNC);
     if ret == -1 {
       let errno = *libc::__errno_location();
       if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
-        return Ok(0);
+        return Ok(RecvRes {
+          recv_size: 0,
+          rxq_ovfl: None,
+        });
       }
       return Err(AppError::IOError("recv", io::Error::last_os_error()));
     }
     debug_assert!(ret >= 0);
-    Ok(ret as usize)
+    Ok(RecvRes {
+      recv_size: ret as usize,
+      rxq_ovfl: get_rxq_ovfl(&msg),
+    })
   }
 }
 
@@ -76,20 +148,31 @@ pub struct RecvfromRes {
   pub recv_size: usize,
   pub src_addr: libc::sockaddr_storage,
   pub src_addr_len: libc::socklen_t,
+
+  /// The socket's `SO_RXQ_OVFL` drop counter, if reported.
+  pub rxq_ovfl: Option<u32>,
+
+  /// The TTL the packet arrived with, if enabled with
+  /// [`enable_recv_ttl`](super::common::enable_recv_ttl).
+  pub ttl: Option<u8>,
 }
 
 pub unsafe fn recvfrom(sock_fd: libc::c_int, recv_buf: &mut [u8]) -> Result<RecvfromRes, AppError> {
   unsafe {
     let mut addr: libc::sockaddr_storage = std::mem::zeroed();
-    let mut addr_len = mem::size_of_val(&addr) as libc::socklen_t;
-    let ret = libc::recvfrom(
-      sock_fd,
-      recv_buf.as_mut_ptr() as *mut _,
-      recv_buf.len(),
-      0,
-      &mut addr as *mut _ as *mut _,
-      &mut addr_len,
-    );
+    let mut iov = libc::iovec {
+      iov_base: recv_buf.as_mut_ptr() as *mut _,
+      iov_len: recv_buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = Default::default();
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_name = &mut addr as *mut _ as *mut _;
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+    let ret = libc::recvmsg(sock_fd, &mut msg, 0);
     if ret == -1 {
       let errno = *libc::__errno_location();
       return Err(AppError::IOError("recvfrom", io::Error::last_os_error()));
@@ -97,7 +180,9 @@ pub unsafe fn recvfrom(sock_fd: libc::c_int, recv_buf: &mut [u8]) -> Result<Recv
     Ok(RecvfromRes {
       recv_size: ret as usize,
       src_addr: addr,
-      src_addr_len: addr_len,
+      src_addr_len: msg.msg_namelen,
+      rxq_ovfl: get_rxq_ovfl(&msg),
+      ttl: get_recv_ttl(&msg),
     })
   }
 }
@@ -124,8 +209,10 @@ pub unsafe fn sendto(
       }
       return Err(AppError::IOError("sendto", io::Error::last_os_error()));
     }
+    // Not `as _`, since serde_json's `PartialEq` impls for `isize` make it
+    // ambiguous.
     #[cfg(debug_assertions)]
-    if ret != buf.len() as _ {
+    if ret != buf.len() as isize {
       unreachable!("Did not send the full packet...?");
       // There is no "partial write" for UDP - if the message is larger than
       // the max length allowable it will return EMSGSIZE.
@@ -5,52 +5,107 @@
 //! same address with SO_REUSEPORT). This works better than sharing the same
 //! socket across threads.
 
-use crate::io_impl::common::{get_sockaddr, setup_recv_socket};
+use crate::affinity::{pin_current_thread, CpuList};
+use crate::control::PeerTable;
+use crate::io_impl::common::{
+  enable_recv_ttl, get_sockaddr, get_socket_local_port, setup_recv_socket, std_socket_addr,
+  wire_size, RxqOvflTracker,
+};
 use crate::io_impl::sys::{recvfrom, sendto};
-use crate::stats;
+use crate::pkt::{clock_now, ReflectMode, Reflector};
+use crate::shutdown::Shutdown;
+use crate::stats::{self, ThreadRole};
 use crate::{errors::AppError, stats::StatsAggregator};
 
 use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Instant;
 
+/// If `peers` is given, packets are also counted per client address, to be
+/// reported over the control connection.  `reflect` says what to do to the
+/// packets before sending them back.
 pub fn syscall_echo(
   listen_addr: &str,
   mtu: usize,
   nb_sockets: usize,
   start_time: Instant,
   stats: &StatsAggregator,
+  cpus: Option<&CpuList>,
+  reflect: ReflectMode,
+  peers: Option<&PeerTable>,
+  shutdown: Shutdown,
 ) -> Result<(), AppError> {
   let resolved_addr = get_sockaddr(listen_addr)?;
+  let af = resolved_addr.0;
+  if let Some(cpus) = cpus {
+    cpus.check_available()?;
+  }
   thread::scope(|scope| {
     for tid in 0..nb_sockets {
       let sock_fd = setup_recv_socket(&resolved_addr)?;
+      if reflect == ReflectMode::Twamp {
+        enable_recv_ttl(sock_fd, af)?;
+      }
+
+      let local_port = unsafe { get_socket_local_port(sock_fd) }?;
+      stats.set_socket_local_port(tid, local_port);
+      let cpu = cpus.map(|c| c.cpu_for_thread(tid));
+      if let Some(cpu) = cpu {
+        eprintln!("Thread {tid} (CPU {cpu}) will use socket {sock_fd}, listening on local port {local_port}.");
+      }
 
       scope.spawn(move || {
+        stats::name_current_thread(ThreadRole::Echo, tid);
+        if let Some(cpu) = cpu {
+          pin_current_thread(cpu).expect("failed to set CPU affinity");
+        }
+        let mut shard = stats.socket_shard(tid);
+        let mut peer_shard = peers.map(|p| p.shard());
         let mut recv_buf = vec![0u8; mtu];
-        loop {
+        let mut reflector = Reflector::new(reflect);
+        let mut rxq_ovfl = RxqOvflTracker::default();
+        let mut drain = shutdown.drain_timer();
+        while !drain.done() {
+          if let Some(peer_shard) = &mut peer_shard {
+            peer_shard.maybe_flush();
+          }
           let recv_res = unsafe { recvfrom(sock_fd, &mut recv_buf) };
           if recv_res.is_err() {
             continue;
           }
           let recv_res = recv_res.unwrap();
+          let recv_clock = if reflector.needs_clock() { clock_now() } else { 0 };
+          let sock_drops = rxq_ovfl.update(recv_res.rxq_ovfl);
           if recv_res.recv_size == 0 {
             // For some reason the kernel sends us spurious 0-length packets occasionally.
             continue;
           }
           let recv_time = stats::get_time_value_now(start_time);
+          let send_size =
+            reflector.reflect(&mut recv_buf, recv_res.recv_size, recv_clock, recv_res.ttl);
           let send_res = unsafe {
             sendto(
               sock_fd,
-              &recv_buf[..recv_res.recv_size],
+              &recv_buf[..send_size],
               &recv_res.src_addr,
               recv_res.src_addr_len,
             )
           };
-          stats.access_step(recv_time, |stats| {
-            stats.rx_packets.fetch_add(1, Ordering::Relaxed);
-            if send_res.is_ok() {
-              stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+          let recv_size = recv_res.recv_size as u64;
+          let recv_wire_size = wire_size(af, recv_res.recv_size);
+          let send_wire_size = wire_size(af, send_size);
+          if let (Some(peer_shard), Some(src_addr)) =
+            (&mut peer_shard, std_socket_addr(&recv_res.src_addr))
+          {
+            peer_shard.count_rx(src_addr, recv_size);
+            peer_shard.count_tx(src_addr, send_res.is_ok());
+          }
+          shard.access_step(recv_time, |stats| {
+            stats.count_rx(1, recv_size, recv_wire_size);
+            stats.rx_sock_drops.fetch_add(sock_drops, Ordering::Relaxed);
+            match send_res {
+              Ok(()) => stats.count_tx(1, send_size as u64, send_wire_size),
+              Err(e) => stats.count_send_errors(e.raw_os_error(), 1),
             }
           });
         }
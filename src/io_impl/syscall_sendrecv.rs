@@ -8,49 +8,165 @@
 //! in the Linux kernel due to hashing by flow.
 //!
 //! See https://lwn.net/Articles/542629/
+//!
+//! With a window, each socket only keeps that many packets in flight, see
+//! [`SendWindow`].
 
 use std::mem::MaybeUninit;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::affinity::{pin_current_thread, CpuList};
 use crate::errors::AppError;
-use crate::io_impl::common::{get_sockaddr, get_socket_local_port, setup_send_socket};
+use crate::io_impl::common::{
+  get_sockaddr, get_socket_local_port, set_ttl, setup_send_socket, wire_size, RxqOvflTracker,
+};
+use crate::io_impl::errqueue::{drain_error_queue, IcmpErrorSummary};
 use crate::io_impl::sys::{recv, send, sendmmsg};
-use crate::pkt::{parse_packet, write_packet};
-use crate::stats::{self, StatsAggregator};
+use crate::io_impl::window::SendWindow;
+use crate::pkt::{clock_now, parse_packet, write_packet, PacketFormat};
+use crate::shutdown::Shutdown;
+use crate::stats::{self, ClockOffsetEstimator, StatsAggregator, StatsShard, ThreadRole};
+use crate::twamp;
+
+/// Write the packet with the given index in the chosen format.
+fn write_any_packet(
+  format: PacketFormat,
+  seed: u64,
+  index: u64,
+  send_time: u64,
+  send_clock: u64,
+  buf: &mut [u8],
+) {
+  match format {
+    PacketFormat::Neuring => write_packet(seed, index, send_time, send_clock, buf),
+    // Sequence numbers wrap around after 2^32 packets, which is fine since we
+    // only use them to tell packets apart.
+    PacketFormat::Twamp => twamp::write_test_packet(index as u32, send_clock, buf),
+  }
+}
+
+/// Settings for [`syscall_sendrecv`].
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+  pub packet_size: usize,
+
+  /// Number of packets to send with each `sendmmsg`, or 1 to use `send`.
+  pub batch_size: usize,
+
+  pub seed: u64,
+  pub packet_format: PacketFormat,
+
+  /// Only send, to a sink that doesn't echo anything back.
+  pub one_way: bool,
+
+  /// Maximum number of packets in flight on each socket, if any, and how long
+  /// to wait for each one's echo.
+  pub window: Option<usize>,
+  pub window_timeout: Duration,
+
+  pub nb_sockets: usize,
+}
 
 pub fn syscall_sendrecv(
   dest_addr: &str,
-  packet_size: usize,
-  batch_size: usize,
-  seed: u64,
-  nb_sockets: usize,
+  options: &SendOptions,
   stats_agg: &StatsAggregator,
   start_time: Instant,
+  cpus: Option<&CpuList>,
+  shutdown: Shutdown,
 ) -> Result<(), AppError> {
+  let SendOptions {
+    packet_size,
+    batch_size,
+    seed,
+    packet_format,
+    one_way,
+    window,
+    window_timeout,
+    nb_sockets,
+  } = *options;
   let index = AtomicU64::new(0);
+  let icmp_summary = IcmpErrorSummary::default();
   let resolved_addr = get_sockaddr(dest_addr)?;
-  thread::scope(|scope| -> Result<(), AppError> {
+  let packet_wire_size = wire_size(resolved_addr.0, packet_size);
+  // Reflected TWAMP packets are never smaller than the reflector's fields.
+  let echo_size = match packet_format {
+    PacketFormat::Neuring => packet_size,
+    PacketFormat::Twamp => packet_size.max(twamp::REFLECTOR_HEAD_SIZE),
+  };
+  let echo_wire_size = wire_size(resolved_addr.0, echo_size);
+  let step_size = stats_agg.step_size();
+  // Timestamps that cross machines come from the wall clock, but are taken
+  // relative to the start so that they line up with the time values.
+  let start_clock = clock_now() - start_time.elapsed().as_nanos() as u64;
+  let clock_at = |since_start: Duration| start_clock + since_start.as_nanos() as u64;
+  if let Some(cpus) = cpus {
+    cpus.check_available()?;
+  }
+  let res = thread::scope(|scope| -> Result<(), AppError> {
     for tid in 0..nb_sockets {
       let sock_fd = setup_send_socket(&resolved_addr)?;
+      if packet_format == PacketFormat::Twamp {
+        set_ttl(sock_fd, resolved_addr.0, twamp::SENDER_TTL)?;
+      }
       let local_port = unsafe { get_socket_local_port(sock_fd) }?;
+      stats_agg.set_socket_local_port(tid, local_port);
 
-      eprintln!("Thread {tid}-send will send from local port {local_port} to {dest_addr}.");
+      // Each socket has a send and a recv thread, which take consecutive CPUs
+      // from the list.
+      let send_cpu = cpus.map(|c| c.cpu_for_thread(tid * 2));
+      let recv_cpu = cpus.map(|c| c.cpu_for_thread(tid * 2 + 1));
+      match (send_cpu, recv_cpu) {
+        (Some(send_cpu), Some(recv_cpu)) => eprintln!(
+          "Thread {tid}-send (CPU {send_cpu}) and {tid}-recv (CPU {recv_cpu}) will use socket {sock_fd}, sending from local port {local_port} to {dest_addr}."
+        ),
+        _ => eprintln!("Thread {tid}-send will send from local port {local_port} to {dest_addr}."),
+      }
       let tx_next_index = &index;
+      let icmp_summary = &icmp_summary;
+      let send_window = window.map(|size| Arc::new(SendWindow::new(size, window_timeout)));
+      let recv_window = send_window.clone();
       scope.spawn(move || {
+        stats::name_current_thread(ThreadRole::Send, tid);
+        if let Some(cpu) = send_cpu {
+          pin_current_thread(cpu).expect("failed to set CPU affinity");
+        }
+        let mut shard = stats_agg.socket_shard(tid);
         if batch_size == 1 {
           // Just use `send` for single-packet batches.
           let mut buf = vec![0u8; packet_size];
-          loop {
+          let mut timed_out = 0;
+          while !shutdown.requested() {
+            if let Some(window) = &send_window {
+              match window.wait_for_room(&shutdown) {
+                Some(n) => timed_out += n,
+                None => break,
+              }
+            }
             let next_ind = tx_next_index.fetch_add(1, Ordering::Relaxed);
-            let time = stats::get_time_value_now(start_time);
-            write_packet(seed, next_ind, time, &mut buf);
-            let _ = unsafe { send(sock_fd, &buf) };
-            stats_agg.access_step(time, |stats| {
-              stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+            let since_start = start_time.elapsed();
+            let time = stats::get_time_value_from_duration(since_start);
+            write_any_packet(packet_format, seed, next_ind, time, clock_at(since_start), &mut buf);
+            if let Some(window) = &send_window {
+              window.sent(next_ind);
+            }
+            let send_res = unsafe { send(sock_fd, &buf) };
+            if let (Some(window), Err(_)) = (&send_window, &send_res) {
+              window.complete(next_ind);
+            }
+            shard.access_step(time, |stats| match send_res {
+              Ok(()) => stats.count_tx(1, packet_size as u64, packet_wire_size),
+              Err(e) => stats.count_send_errors(e.raw_os_error(), 1),
             });
           }
+          if timed_out > 0 {
+            eprintln!(
+              "Thread {tid}-send gave up waiting for {timed_out} echoes after {window_timeout:?}."
+            );
+          }
         } else {
           // Pre-allocate a bunch of buffers that we will re-use for each batch.
           let mut iovec_buf: Box<[MaybeUninit<libc::iovec>]> = Box::new_uninit_slice(batch_size);
@@ -58,8 +174,10 @@ pub fn syscall_sendrecv(
             Box::new_uninit_slice(batch_size);
           let mut pkt_buf: Vec<u8> = vec![0u8; packet_size * batch_size];
 
-          loop {
-            let time = stats::get_time_value_now(start_time);
+          while !shutdown.requested() {
+            let since_start = start_time.elapsed();
+            let time = stats::get_time_value_from_duration(since_start);
+            let clock = clock_at(since_start);
 
             // To not have to do atomics for each packet, we reserve a chunk
             // of indices up-front.
@@ -70,7 +188,7 @@ pub fn syscall_sendrecv(
               for i in 0..batch_size {
                 let pkt_index = reserved_ind_chunk_start + i as u64;
                 let pkt_slice = &mut pkt_buf[i * packet_size..(i + 1) * packet_size];
-                write_packet(seed, pkt_index, time, pkt_slice);
+                write_any_packet(packet_format, seed, pkt_index, time, clock, pkt_slice);
 
                 iovec_buf[i] = MaybeUninit::new(libc::iovec {
                   iov_base: pkt_slice.as_ptr() as *const libc::c_void as *mut _,
@@ -91,60 +209,147 @@ pub fn syscall_sendrecv(
                 });
               }
 
-              let _ = sendmmsg(
+              let (nb_sent, err) = sendmmsg(
                 sock_fd,
                 MaybeUninit::slice_assume_init_mut(&mut mmsghdr_buf[..]),
               );
-              stats_agg.access_step(time, |stats| {
-                stats
-                  .tx_packets
-                  .fetch_add(batch_size as u64, Ordering::Relaxed);
+              shard.access_step(time, |stats| {
+                let nb_sent = nb_sent as u64;
+                stats.count_tx(
+                  nb_sent,
+                  nb_sent * packet_size as u64,
+                  nb_sent * packet_wire_size,
+                );
+                if let Some(err) = err {
+                  // Everything after the failed packet was not sent either.
+                  stats.count_send_errors(err.raw_os_error(), batch_size as u64 - nb_sent);
+                }
               });
             }
           }
         }
       });
 
+      if one_way {
+        // Nothing comes back from a sink.
+        continue;
+      }
+
       // recv loop
       scope.spawn(move || {
+        stats::name_current_thread(ThreadRole::Recv, tid);
+        if let Some(cpu) = recv_cpu {
+          pin_current_thread(cpu).expect("failed to set CPU affinity");
+        }
+        let mut shard = stats_agg.socket_shard(tid);
         // Use a slightly larger buffer to detect wrong packet sizes.
         let mut recv_buf = vec![0u8; packet_size + 4];
-        loop {
+        let mut rxq_ovfl = RxqOvflTracker::default();
+        let mut warned_late = false;
+        let mut clock_offset = ClockOffsetEstimator::default();
+        let mut drain = shutdown.drain_timer();
+        // Count what's in the error queue in the step containing `time`.
+        let read_error_queue = |shard: &mut StatsShard, time: u64| {
+          if let Ok(icmp_errors) = drain_error_queue(sock_fd) {
+            if !icmp_errors.is_empty() {
+              icmp_summary.add(&icmp_errors);
+              shard.access_step(time, |stats| icmp_errors.record(stats));
+            }
+          }
+        };
+        let mut error_queue_step = 0;
+        // Keep receiving for a while after sending has stopped, for the
+        // packets still in flight.
+        while !drain.done() {
           let recv_res = unsafe { recv(sock_fd, &mut recv_buf) };
           if recv_res.is_err() {
+            // Most likely an ICMP error was reported, see what's in the error
+            // queue.
+            read_error_queue(&mut shard, stats::get_time_value_now(start_time));
             continue;
           }
-          let recv_size = recv_res.unwrap();
-          let recv_time = stats::get_time_value_now(start_time);
-          if recv_size != packet_size {
+          let recv_res = recv_res.unwrap();
+          let recv_size = recv_res.recv_size;
+          let since_start = start_time.elapsed();
+          let recv_time = stats::get_time_value_from_duration(since_start);
+          let recv_clock = clock_at(since_start);
+          // recv only fails for some of the errors, and not at all while
+          // echoes keep arriving, so also look at the error queue once per
+          // step.
+          let step = recv_time / step_size;
+          if step != error_queue_step {
+            error_queue_step = step;
+            read_error_queue(&mut shard, recv_time);
+          }
+          let sock_drops = rxq_ovfl.update(recv_res.rxq_ovfl);
+          if sock_drops > 0 {
+            shard.access_step(recv_time, |stats| {
+              stats.rx_sock_drops.fetch_add(sock_drops, Ordering::Relaxed);
+            });
+          }
+          if recv_size != echo_size {
             // Ignore
             continue;
           }
-          match parse_packet(seed, &recv_buf[0..recv_size]) {
-            Ok(pkt_header) => {
+          let parsed = match packet_format {
+            PacketFormat::Neuring => parse_packet(seed, &recv_buf[0..recv_size]).ok(),
+            PacketFormat::Twamp => twamp::parse_reflected(&recv_buf[0..recv_size], start_clock),
+          };
+          match parsed {
+            Some(pkt_header) => {
+              if let Some(window) = &recv_window {
+                window.complete(pkt_header.index);
+              }
               let send_time = pkt_header.send_time;
               if send_time > recv_time {
                 // Ignore
                 continue;
               }
-              stats_agg.access_step(recv_time, |stats| {
-                stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+              shard.access_step(recv_time, |stats| {
+                stats.count_rx(1, recv_size as u64, echo_wire_size);
               });
-              stats_agg.access_step(send_time, |stats| {
-                stats.rx_packets_sent_here.fetch_add(1, Ordering::Relaxed);
-                stats
-                  .total_latency_sent_here
-                  .fetch_add(recv_time - send_time, Ordering::Relaxed);
+              let latency = recv_time - send_time;
+              // Both clocks are ours, so this is as precise as they are.
+              let latency_us = recv_clock.saturating_sub(pkt_header.send_clock) / 1000;
+              // Echo servers in reflector mode tell us when they had the
+              // packet, which splits the round trip into one-way delays.
+              let one_way_delays = (pkt_header.reflect_send_clock != 0)
+                .then(|| clock_offset.measure(&pkt_header, recv_clock));
+              let counted = shard.access_step(send_time, |stats| {
+                stats.record_latency(latency, latency_us);
+                if let Some(delays) = &one_way_delays {
+                  stats.record_one_way(delays);
+                }
               });
+              if !counted {
+                // The send step was already written out, so count it where it
+                // arrived instead of letting it show up as loss.
+                shard.access_step(recv_time, |stats| stats.record_late(latency));
+                if !warned_late {
+                  warned_late = true;
+                  eprintln!(
+                    "Warn: thread {tid}-recv received an echo {:?} after sending it, after its stats were already written.  Consider a larger --stats-evict-threshold-secs.",
+                    stats::duration_from_time_value(latency)
+                  );
+                }
+              }
             }
-            Err(_) => {
+            None => {
               // Ignore
               continue;
             }
           };
         }
+        if let Some(offset) = clock_offset.offset() {
+          eprintln!(
+            "Thread {tid}-recv estimated the reflector's clock to be {:+.3}ms from ours.",
+            offset as f64 / 1e6
+          );
+        }
       });
     }
     Ok(())
-  })
+  });
+  icmp_summary.print();
+  res
 }
@@ -0,0 +1,207 @@
+//! A multi-threaded sink for one-way tests, receiving packets with normal
+//! `recv` syscalls and never sending anything back, so that the path only
+//! carries the load in one direction.
+//!
+//! Latency is one-way, from the wall clock time the packet was sent at to the
+//! one it arrived at, so it is only meaningful if the clocks of both machines
+//! are synchronized, e.g. with PTP.
+//!
+//! We don't see what the sender sent, so we infer it from the packet indices:
+//! when a packet's index skips ahead of the highest index seen so far, the
+//! skipped packets were sent at about the same time, and are counted as sent in
+//! its step.  Packets that arrive out of order later make up for it.  With
+//! this, the tx columns of a sink are what the sender sent, and the usual loss
+//! and drop rate figures work as they do for echo tests, except that packets
+//! lost at the very end of the test go unnoticed.
+//!
+//! The indices of a sender start over with each test, so the sink needs to
+//! know when a new test starts.  With --control-addr, it starts over when the
+//! sender's control session does, and only allows one test from each address
+//! at a time.  Without it, it can't tell tests apart, so it should be
+//! restarted between tests from the same sender.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::affinity::{pin_current_thread, CpuList};
+use crate::control::PeerTable;
+use crate::errors::AppError;
+use crate::io_impl::common::{
+  get_sockaddr, get_socket_local_port, setup_recv_socket, std_socket_addr, wire_size,
+  RxqOvflTracker,
+};
+use crate::io_impl::sys::recvfrom;
+use crate::pkt::{clock_now, parse_packet, PACKET_HEAD_SIZE};
+use crate::shutdown::Shutdown;
+use crate::stats::{self, StatsAggregator, ThreadRole};
+
+/// The highest packet index seen from each sender.  The indices of a sender
+/// are shared by all its sockets, which may be received by any of ours, so
+/// this is shared by all threads, and keyed by address rather than port.
+#[derive(Default)]
+struct IndexTracker {
+  next_index: RwLock<HashMap<IpAddr, Arc<RunIndex>>>,
+}
+
+/// The next packet index of one run of a sender.
+#[derive(Default)]
+struct RunIndex {
+  run: u64,
+  next: AtomicU64,
+}
+
+impl IndexTracker {
+  /// Record a packet index from `sender` in `run`, and return how many new
+  /// packets it shows were sent: the packet itself and any skipped before it,
+  /// or 0 if it was already accounted for.  A later run starts over from 0.
+  fn advance(&self, sender: IpAddr, run: u64, index: u64) -> u64 {
+    let next = self.next_index.read().unwrap().get(&sender).cloned();
+    let next = match next {
+      // Threads may not all have seen the new run yet, so older ones just
+      // count towards the newest.
+      Some(next) if next.run >= run => next,
+      _ => {
+        let mut next_index = self.next_index.write().unwrap();
+        let next = next_index.entry(sender).or_default();
+        if next.run < run {
+          *next = Arc::new(RunIndex { run, next: AtomicU64::new(0) });
+        }
+        next.clone()
+      }
+    };
+    let prev = next.next.fetch_max(index + 1, Ordering::Relaxed);
+    (index + 1).saturating_sub(prev)
+  }
+}
+
+/// If `peers` is given, packets are also counted per client address, to be
+/// reported over the control connection.
+pub fn syscall_sink(
+  listen_addr: &str,
+  mtu: usize,
+  seed: u64,
+  nb_sockets: usize,
+  start_time: Instant,
+  stats: &StatsAggregator,
+  cpus: Option<&CpuList>,
+  peers: Option<&PeerTable>,
+  shutdown: Shutdown,
+) -> Result<(), AppError> {
+  let resolved_addr = get_sockaddr(listen_addr)?;
+  let af = resolved_addr.0;
+  if let Some(cpus) = cpus {
+    cpus.check_available()?;
+  }
+  let start_clock = clock_now() - start_time.elapsed().as_nanos() as u64;
+  let tracker = IndexTracker::default();
+  let warned_clock = AtomicBool::new(false);
+  thread::scope(|scope| {
+    for tid in 0..nb_sockets {
+      let sock_fd = setup_recv_socket(&resolved_addr)?;
+
+      let local_port = unsafe { get_socket_local_port(sock_fd) }?;
+      stats.set_socket_local_port(tid, local_port);
+      let cpu = cpus.map(|c| c.cpu_for_thread(tid));
+      if let Some(cpu) = cpu {
+        eprintln!("Thread {tid} (CPU {cpu}) will use socket {sock_fd}, listening on local port {local_port}.");
+      }
+
+      let tracker = &tracker;
+      let warned_clock = &warned_clock;
+      scope.spawn(move || {
+        stats::name_current_thread(ThreadRole::Recv, tid);
+        if let Some(cpu) = cpu {
+          pin_current_thread(cpu).expect("failed to set CPU affinity");
+        }
+        let mut shard = stats.socket_shard(tid);
+        let mut peer_shard = peers.map(|p| p.shard());
+        let mut recv_buf = vec![0u8; mtu];
+        let mut rxq_ovfl = RxqOvflTracker::default();
+        let mut warned_late = false;
+        let mut drain = shutdown.drain_timer();
+        while !drain.done() {
+          if let Some(peer_shard) = &mut peer_shard {
+            peer_shard.maybe_flush();
+          }
+          let recv_res = unsafe { recvfrom(sock_fd, &mut recv_buf) };
+          if recv_res.is_err() {
+            continue;
+          }
+          let recv_res = recv_res.unwrap();
+          let sock_drops = rxq_ovfl.update(recv_res.rxq_ovfl);
+          let recv_size = recv_res.recv_size;
+          if recv_size == 0 {
+            // For some reason the kernel sends us spurious 0-length packets occasionally.
+            continue;
+          }
+          let since_start = start_time.elapsed();
+          let recv_time = stats::get_time_value_from_duration(since_start);
+          let recv_clock = start_clock + since_start.as_nanos() as u64;
+          let recv_wire_size = wire_size(af, recv_size);
+          let src_addr = std_socket_addr(&recv_res.src_addr);
+          if let (Some(peer_shard), Some(src_addr)) = (&mut peer_shard, src_addr) {
+            peer_shard.count_rx(src_addr, recv_size as u64);
+          }
+          shard.access_step(recv_time, |stats| {
+            stats.count_rx(1, recv_size as u64, recv_wire_size);
+            stats.rx_sock_drops.fetch_add(sock_drops, Ordering::Relaxed);
+          });
+
+          let Some(src_addr) = src_addr else {
+            continue;
+          };
+          if recv_size < PACKET_HEAD_SIZE {
+            continue;
+          }
+          let Ok(pkt_header) = parse_packet(seed, &recv_buf[..recv_size]) else {
+            continue;
+          };
+          if pkt_header.send_clock > recv_clock && !warned_clock.swap(true, Ordering::Relaxed) {
+            eprintln!(
+              "Warn: received a packet sent {:?} in the future.  One-way latency needs the clocks of the sender and the sink to be synchronized.",
+              Duration::from_nanos(pkt_header.send_clock - recv_clock)
+            );
+          }
+          let latency_ns = recv_clock.saturating_sub(pkt_header.send_clock);
+          let latency = stats::get_time_value_from_duration(Duration::from_nanos(latency_ns));
+          // The step it was sent in on our time line, which can't be later
+          // than now, whatever the clocks say.
+          let send_time = recv_time.saturating_sub(latency);
+          let run = peer_shard.as_ref().and_then(|p| p.run_of(src_addr.ip()));
+          let new_packets = tracker.advance(src_addr.ip(), run.unwrap_or(0), pkt_header.index);
+          let record = |stats: &stats::Stats| {
+            stats.count_tx(
+              new_packets,
+              new_packets * recv_size as u64,
+              new_packets * recv_wire_size,
+            );
+          };
+          let counted = shard.access_step(send_time, |stats| {
+            record(stats);
+            stats.record_latency(latency, latency_ns / 1000);
+          });
+          if !counted {
+            // The send step was already written out, so count it where it
+            // arrived instead of letting it show up as loss.
+            shard.access_step(recv_time, |stats| {
+              record(stats);
+              stats.record_late(latency);
+            });
+            if !warned_late {
+              warned_late = true;
+              eprintln!(
+                "Warn: thread {tid} received a packet {:?} after it was sent, after its stats were already written.  Consider a larger --stats-evict-threshold-secs.",
+                stats::duration_from_time_value(latency)
+              );
+            }
+          }
+        }
+      });
+    }
+    Ok(())
+  })
+}
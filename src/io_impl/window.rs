@@ -0,0 +1,76 @@
+//! The packets a socket has in flight in closed-loop mode, where it only sends
+//! another packet when an echo comes back or the oldest one times out.  At low
+//! load, this keeps our own queues empty, so that latency is the network's
+//! round trip time, like with sockperf's ping-pong mode.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::shutdown::Shutdown;
+
+/// How long to wait at most before checking for shutdown again.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Shared by the send and recv threads of a socket.
+#[derive(Debug)]
+pub struct SendWindow {
+  size: usize,
+  timeout: Duration,
+  /// Indices and send times of the packets in flight, oldest first.  Only the
+  /// low 32 bits of the indices are kept, since TWAMP sequence numbers wrap
+  /// around there, and packets that far apart are never in flight together.
+  in_flight: Mutex<VecDeque<(u32, Instant)>>,
+  room: Condvar,
+}
+
+impl SendWindow {
+  pub fn new(size: usize, timeout: Duration) -> Self {
+    Self {
+      size,
+      timeout,
+      in_flight: Mutex::new(VecDeque::with_capacity(size)),
+      room: Condvar::new(),
+    }
+  }
+
+  /// Wait until there is room for another packet, giving up on packets that
+  /// have been in flight for longer than the timeout.  Returns how many were
+  /// given up on, or `None` if shutdown was requested in the meantime.
+  pub fn wait_for_room(&self, shutdown: &Shutdown) -> Option<u64> {
+    let mut in_flight = self.in_flight.lock().unwrap();
+    let mut timed_out = 0;
+    loop {
+      if shutdown.requested() {
+        return None;
+      }
+      let now = Instant::now();
+      while in_flight.front().is_some_and(|&(_, sent_at)| sent_at + self.timeout <= now) {
+        in_flight.pop_front();
+        timed_out += 1;
+      }
+      if in_flight.len() < self.size {
+        return Some(timed_out);
+      }
+      let oldest = in_flight.front().unwrap().1;
+      let wait = (oldest + self.timeout - now).min(SHUTDOWN_POLL);
+      in_flight = self.room.wait_timeout(in_flight, wait).unwrap().0;
+    }
+  }
+
+  /// Add a packet to the window.  This must be called before sending it, so
+  /// that its echo can't come back before.
+  pub fn sent(&self, index: u64) {
+    self.in_flight.lock().unwrap().push_back((index as u32, Instant::now()));
+  }
+
+  /// Remove a packet from the window, because its echo came back or it could
+  /// not be sent.  Packets that were already given up on are ignored.
+  pub fn complete(&self, index: u64) {
+    let mut in_flight = self.in_flight.lock().unwrap();
+    if let Some(pos) = in_flight.iter().position(|&(i, _)| i == index as u32) {
+      in_flight.remove(pos);
+      self.room.notify_one();
+    }
+  }
+}
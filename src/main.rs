@@ -1,19 +1,36 @@
 #![feature(new_uninit)]
 #![feature(maybe_uninit_slice)]
 
-use clap::{Parser, Subcommand};
+use affinity::CpuList;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use control::{ControlClient, ControlServer, PeerTable, ServerInfo, TestMode, TestParams};
 use errors::AppError;
-use stats::{get_time_value_from_duration, StatsAggregator};
+use pkt::{PacketFormat, ReflectMode};
+use report::{AnalyzeOptions, CompareOptions, StatsRecording};
+use shutdown::Shutdown;
+use stats::{
+  get_time_value_from_duration, get_time_value_now, ConsoleMode, ConsoleSink, PrometheusExporter,
+  RunInfo, RunSummary, StatsAggregator, StatsFormat,
+};
 use std::{
-  path::PathBuf,
+  fs,
+  path::{Path, PathBuf},
   process,
-  time::{Duration, Instant},
+  sync::atomic::{AtomicBool, Ordering},
+  thread,
+  time::{Duration, Instant, SystemTime},
 };
 
+mod affinity;
+mod config;
+mod control;
 mod errors;
 mod io_impl;
 mod pkt;
+mod report;
+mod shutdown;
 mod stats;
+mod twamp;
 
 #[derive(Parser)]
 #[command(version)]
@@ -36,21 +53,93 @@ pub(crate) struct Cli {
   )]
   seed: u64,
 
+  #[arg(global(true), long, value_enum, default_value_t = PacketFormat::Neuring)]
+  /// Layout of the test packets.  Echo servers reflect TWAMP packets with
+  /// their timestamps filled in, so they can answer TWAMP-Light senders, and
+  /// clients can measure against TWAMP-Light reflectors.  Sinks only take
+  /// the native format.
+  packet_format: PacketFormat,
+
   #[arg(global(true), short = 's', long, required = false)]
-  /// Output packet stats to CSV.
+  /// Output packet stats to a file.  Use "-" for stdout, or udp://host:port or
+  /// tcp://host:port to send them over the network.
   stats_file: Option<PathBuf>,
 
+  #[arg(global(true), long, value_enum)]
+  /// Format of the stats file.  If not given, it is guessed from the file
+  /// extension (.jsonl, .ndjson or .json for JSON Lines, .influx or .lp for
+  /// InfluxDB line protocol, .graphite for Graphite), defaulting to CSV.  It
+  /// is required for udp:// and tcp:// destinations.
+  stats_format: Option<StatsFormat>,
+
+  #[arg(global(true), long)]
+  /// Also write stats for each socket separately, after the totals of each
+  /// step, tagged with the socket number and local port.
+  stats_per_socket: bool,
+
+  #[arg(global(true), long, required = false)]
+  /// Serve cumulative stats in the Prometheus text format at
+  /// http://<address>/metrics, e.g. "0.0.0.0:9100".  Stats are only updated as
+  /// steps are dumped, so they lag behind by --stats-evict-threshold-secs.
+  metrics_listen: Option<String>,
+
+  #[arg(global(true), long, value_enum)]
+  /// Show live stats on stderr.  Stats are shown as steps are dumped, so they
+  /// lag behind by --stats-evict-threshold-secs.
+  console: Option<ConsoleMode>,
+
+  #[arg(global(true), long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+  /// Interval in milliseconds between lines of --console output.
+  console_interval_ms: u64,
+
   #[arg(global(true), short = 'i', long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
   /// Interval in milliseconds between stat steps.
   stats_interval_ms: u64,
 
   #[arg(global(true), short = 't', long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
-  /// Number of seconds between stats dump.
+  /// Number of seconds of stats to keep in memory.  Steps are normally dumped
+  /// long before that, see --stats-evict-threshold-secs.
   stats_evict_interval_secs: u64,
 
   #[arg(global(true), short = 'T', long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
-  /// On each stats dump, stats older than this many seconds will be dumped.
+  /// Stats are dumped once they are older than this many seconds.  Echoes
+  /// arriving later than that are counted as late, and as dropped in the
+  /// drop_rate of the step they were sent in, but not in net_drop_rate.
   stats_evict_threshold_secs: u64,
+
+  #[arg(global(true), long, default_value_t = 1000)]
+  /// On SIGINT / SIGTERM, stop sending and keep receiving for this many
+  /// milliseconds before exiting, so that packets in flight are not counted
+  /// as lost.
+  drain_ms: u64,
+
+  #[arg(global(true), long, required = false)]
+  /// On exit, write the totals of the run to this file as a JSON object, which
+  /// can be given to the compare subcommand.
+  summary_file: Option<PathBuf>,
+
+  #[arg(global(true), long, required = false)]
+  /// Address of the TCP control connection, in the form host:port.  Clients
+  /// announce the test there and wait for the server to accept it before
+  /// sending.  Echo servers listen there, and reject tests they can't run,
+  /// e.g. with packets larger than their mtu.
+  control_addr: Option<String>,
+
+  #[arg(global(true), long, default_value_t = 500)]
+  /// With --control-addr, how many milliseconds the client waits after the
+  /// server accepts the test before it starts sending.  Servers keep a single
+  /// clock for all tests, so their steps don't line up with the client's.
+  start_delay_ms: u64,
+
+  #[arg(global(true), long, required = false)]
+  /// Read settings from a scenario file, in TOML, or JSON if the extension is
+  /// .json.  The mode is the "mode" key, and the other keys are names of
+  /// flags.  Flags given on the command line take precedence.
+  config: Option<PathBuf>,
+
+  #[arg(global(true), long)]
+  /// Print the effective settings as a scenario file and exit.
+  dump_config: bool,
 }
 
 fn positive_usize_parser(s: &str) -> Result<usize, &'static str> {
@@ -61,18 +150,41 @@ fn positive_usize_parser(s: &str) -> Result<usize, &'static str> {
   Ok(val)
 }
 
-fn make_stats_aggregator_from_arg(cli: &Cli) -> Result<stats::StatsAggregator, AppError> {
-  let stats_file = &cli.stats_file;
-  let writer;
-  if let Some(stats_file) = stats_file {
-    writer = Some(stats::get_csv_writer(stats_file)?);
-  } else {
-    writer = None;
+/// A number of seconds that can be turned into a `Duration`, so not negative,
+/// NaN or huge.
+fn secs_parser(s: &str) -> Result<f64, &'static str> {
+  let val: f64 = s.parse().map_err(|_| "Invalid number")?;
+  Duration::try_from_secs_f64(val).map_err(|_| "Invalid number of seconds")?;
+  Ok(val)
+}
+
+fn make_stats_aggregator_from_arg(
+  cli: &Cli,
+  run_info: &RunInfo,
+  summary: &RunSummary,
+) -> Result<stats::StatsAggregator, AppError> {
+  let mut sinks: Vec<Box<dyn stats::StatsSink>> = vec![Box::new(summary.clone())];
+  if let Some(stats_file) = &cli.stats_file {
+    sinks.push(stats::open_stats_file(stats_file, cli.stats_format, run_info)?);
+  }
+  if let Some(metrics_listen) = &cli.metrics_listen {
+    sinks.push(Box::new(PrometheusExporter::start(metrics_listen)?));
   }
+  if let Some(console) = cli.console {
+    // Intervals shorter than a step would be meaningless.
+    let interval_ms = cli.console_interval_ms.max(cli.stats_interval_ms);
+    sinks.push(Box::new(ConsoleSink::new(
+      console,
+      get_time_value_from_duration(Duration::from_millis(interval_ms)),
+      run_info,
+    )));
+  }
+  let writer = Some(stats::make_stats_writer(sinks));
   let stats = StatsAggregator::new(
     get_time_value_from_duration(Duration::from_millis(cli.stats_interval_ms)),
     get_time_value_from_duration(Duration::from_secs(cli.stats_evict_interval_secs)),
     get_time_value_from_duration(Duration::from_secs(cli.stats_evict_threshold_secs)),
+    if run_info.per_socket { run_info.nb_sockets } else { 0 },
     writer,
   );
   Ok(stats)
@@ -97,6 +209,29 @@ enum Commands {
     /// Number of sockets to use.  Each socket will be handled by 2 new threads
     /// - one for sending and one for receiving.
     nb_sockets: usize,
+
+    #[arg(long)]
+    /// Pin threads to these CPUs, e.g. "0-3,8".  The send and recv threads of
+    /// each socket take consecutive CPUs from the list, wrapping around if
+    /// there are more threads than CPUs.
+    cpus: Option<CpuList>,
+
+    #[arg(long)]
+    /// Only send, to a sink rather than an echo server.  Loss and one-way
+    /// latency are then measured by the sink.
+    one_way: bool,
+
+    #[arg(long, value_parser = positive_usize_parser)]
+    /// Closed-loop mode: keep at most this many packets in flight on each
+    /// socket, and only send another one when an echo comes back or the oldest
+    /// one times out.  1 is classic ping-pong, for clean round trip times at
+    /// low load.  This can't be used with --batch-size or --one-way.
+    window: Option<usize>,
+
+    #[arg(long)]
+    /// How long to wait for an echo in closed-loop mode before giving up on it
+    /// and sending the next packet, in milliseconds [default: 1000]
+    window_timeout_ms: Option<u64>,
   },
 
   /// An echo server with normal syscalls
@@ -114,6 +249,42 @@ enum Commands {
     #[arg(long, value_parser = positive_usize_parser, default_value_t = 2000)]
     /// The maximum size of a packet we will process
     mtu: usize,
+
+    #[arg(long)]
+    /// Pin threads to these CPUs, e.g. "0-3,8".  Each socket's thread takes the
+    /// next CPU from the list, wrapping around if there are more threads than
+    /// CPUs.
+    cpus: Option<CpuList>,
+
+    #[arg(long)]
+    /// Stamp the times each packet was received and sent back at into its
+    /// header, so that clients can tell forward from reverse delay.  TWAMP
+    /// packets always get them.
+    reflect_timestamps: bool,
+  },
+
+  /// Receive and count packets without sending them back, for one-way tests
+  /// with syscall-send --one-way.  Latency is one-way, so the clocks of both
+  /// ends need to be synchronized.
+  #[clap(name = "sink")]
+  Sink {
+    #[arg(required = true)]
+    /// Address to listen on, in the form host:port
+    server_addr: String,
+
+    #[arg(long, short = 'j', value_parser = positive_usize_parser, default_value_t = 1)]
+    /// Number of sockets to use.  Each socket will be handled by a new thread.
+    nb_sockets: usize,
+
+    #[arg(long, value_parser = positive_usize_parser, default_value_t = 2000)]
+    /// The maximum size of a packet we will process
+    mtu: usize,
+
+    #[arg(long)]
+    /// Pin threads to these CPUs, e.g. "0-3,8".  Each socket's thread takes the
+    /// next CPU from the list, wrapping around if there are more threads than
+    /// CPUs.
+    cpus: Option<CpuList>,
   },
 
   /// io_uring-based echo server
@@ -146,31 +317,480 @@ enum Commands {
     #[arg(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 32)]
     /// Number of recv requests to send to the kernel.
     nb_recv: u32,
+
+    #[arg(long)]
+    /// Stamp the time each packet was received at into its header, so that
+    /// clients can tell forward from reverse delay.  TWAMP packets always get
+    /// them.
+    reflect_timestamps: bool,
+  },
+
+  /// Summarize a stats file written with --stats-file, in any format.  Use
+  /// --stats-format if the format can't be guessed from the extension.
+  Analyze {
+    #[arg(required = true)]
+    /// The stats file to read
+    file: PathBuf,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps before this many seconds into the run.
+    from_secs: Option<f64>,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps from this many seconds into the run.
+    to_secs: Option<f64>,
+
+    #[arg(long, default_value_t = 5)]
+    /// Number of worst steps to list.
+    top: usize,
+
+    #[arg(long, default_value_t = 0.05)]
+    /// Report a loss burst where the drop rate exceeds the median by more
+    /// than this fraction.
+    loss_burst_threshold: f64,
+
+    #[arg(long, default_value_t = 3.0)]
+    /// Report a latency spike where the average latency exceeds this many
+    /// times the median.
+    latency_spike_factor: f64,
+  },
+
+  /// Compare a candidate run against a baseline, and exit with an error if
+  /// any of the given thresholds are exceeded.  Each run can be a stats file
+  /// in any format, or a --summary-file.
+  Compare {
+    #[arg(required = true)]
+    /// Stats or summary file of the baseline run
+    baseline: PathBuf,
+
+    #[arg(required = true)]
+    /// Stats or summary file of the candidate run
+    candidate: PathBuf,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps before this many seconds into each run.
+    from_secs: Option<f64>,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps from this many seconds into each run.
+    to_secs: Option<f64>,
+
+    #[arg(long)]
+    /// Fail if tx or rx packets per second drop by more than this percentage.
+    max_throughput_drop: Option<f64>,
+
+    #[arg(long)]
+    /// Fail if loss increases by more than this many percentage points.
+    max_loss_increase: Option<f64>,
+
+    #[arg(long)]
+    /// Fail if the average or p99 latency increases by more than this
+    /// percentage.
+    max_latency_increase: Option<f64>,
+
+    #[arg(long, default_value_t = 0.05)]
+    /// p-value under which a change is considered significant.
+    alpha: f64,
+
+    #[arg(long)]
+    /// Only fail on significant changes.  Latency percentiles, which have no
+    /// significance test, are always checked.
+    significant_only: bool,
   },
+
+  /// Render a stats file as an SVG chart, with panels for throughput, drop
+  /// rate and latency.
+  Plot {
+    #[arg(required = true)]
+    /// The stats file to read
+    file: PathBuf,
+
+    #[arg(long, short = 'o')]
+    /// Where to write the SVG.  Use "-" for stdout.  Defaults to the stats
+    /// file with an .svg extension.
+    output: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Title of the chart.  Defaults to the name of the stats file.
+    title: Option<String>,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps before this many seconds into the run.
+    from_secs: Option<f64>,
+
+    #[arg(long, value_parser = secs_parser)]
+    /// Ignore steps from this many seconds into the run.
+    to_secs: Option<f64>,
+
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(200..))]
+    /// Width of the chart in pixels.
+    width: u32,
+  },
+}
+
+impl Commands {
+  /// The name of the subcommand, as used on the command line.
+  fn name(&self) -> &'static str {
+    match self {
+      Commands::SyscallSendrecv { .. } => "syscall-send",
+      Commands::SyscallEcho { .. } => "syscall-echo",
+      Commands::Sink { .. } => "sink",
+      Commands::IoUringEcho { .. } => "io-uring-echo",
+      Commands::Analyze { .. } => "analyze",
+      Commands::Compare { .. } => "compare",
+      Commands::Plot { .. } => "plot",
+    }
+  }
+
+  /// Whether this subcommand only processes existing files, rather than
+  /// sending or receiving packets.
+  fn is_offline(&self) -> bool {
+    matches!(
+      self,
+      Commands::Analyze { .. } | Commands::Compare { .. } | Commands::Plot { .. }
+    )
+  }
+
+  fn nb_sockets(&self) -> usize {
+    match *self {
+      Commands::SyscallSendrecv { nb_sockets, .. }
+      | Commands::SyscallEcho { nb_sockets, .. }
+      | Commands::Sink { nb_sockets, .. }
+      | Commands::IoUringEcho { nb_sockets, .. } => nb_sockets,
+      Commands::Analyze { .. } | Commands::Compare { .. } | Commands::Plot { .. } => 0,
+    }
+  }
+}
+
+/// Read a stats file, only keeping the steps between the given seconds.
+fn read_recording(
+  path: &Path,
+  format: Option<StatsFormat>,
+  from_secs: Option<f64>,
+  to_secs: Option<f64>,
+) -> Result<StatsRecording, AppError> {
+  let secs_to_time_value = |secs| get_time_value_from_duration(Duration::from_secs_f64(secs));
+  let mut rec = StatsRecording::read(path, format)?;
+  if !rec.is_summary {
+    rec.trim(from_secs.map(secs_to_time_value), to_secs.map(secs_to_time_value));
+  } else if from_secs.is_some() || to_secs.is_some() {
+    // A summary can't be cut, so compare it whole.
+    eprintln!(
+      "Note: {} is a run summary, so --from-secs and --to-secs don't apply to it.",
+      path.display()
+    );
+  }
+  if rec.steps.is_empty() {
+    return Err(AppError::EmptyStatsFile(path.display().to_string()));
+  }
+  Ok(rec)
+}
+
+/// Run one of the subcommands that process stats files.
+fn run_offline(cli: &Cli) -> Result<(), AppError> {
+  match cli.command {
+    Commands::Analyze {
+      ref file,
+      from_secs,
+      to_secs,
+      top,
+      loss_burst_threshold,
+      latency_spike_factor,
+    } => {
+      let rec = read_recording(file, cli.stats_format, from_secs, to_secs)?;
+      report::analyze(
+        &rec,
+        &AnalyzeOptions {
+          top,
+          loss_burst_threshold,
+          latency_spike_factor,
+        },
+      );
+      Ok(())
+    }
+    Commands::Compare {
+      ref baseline,
+      ref candidate,
+      from_secs,
+      to_secs,
+      max_throughput_drop,
+      max_loss_increase,
+      max_latency_increase,
+      alpha,
+      significant_only,
+    } => {
+      let baseline = read_recording(baseline, cli.stats_format, from_secs, to_secs)?;
+      let candidate = read_recording(candidate, cli.stats_format, from_secs, to_secs)?;
+      let exceeded = report::compare(
+        &baseline,
+        &candidate,
+        &CompareOptions {
+          max_throughput_drop,
+          max_loss_increase,
+          max_latency_increase,
+          alpha,
+          significant_only,
+        },
+      );
+      if exceeded > 0 {
+        return Err(AppError::ThresholdsExceeded(exceeded));
+      }
+      Ok(())
+    }
+    Commands::Plot {
+      ref file,
+      ref output,
+      ref title,
+      from_secs,
+      to_secs,
+      width,
+    } => {
+      let rec = read_recording(file, cli.stats_format, from_secs, to_secs)?;
+      let title = match title {
+        Some(t) => t.clone(),
+        None => file.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+      };
+      let svg = report::plot(&rec, &title, width);
+      let output = output.clone().unwrap_or_else(|| file.with_extension("svg"));
+      if output.as_os_str() == "-" {
+        print!("{svg}");
+        return Ok(());
+      }
+      fs::write(&output, svg).map_err(|e| AppError::IOError("write plot", e))
+    }
+    _ => unreachable!(),
+  }
+}
+
+/// Announce the test to the server, if this is a client and --control-addr
+/// was given.
+fn start_control_client(cli: &Cli) -> Result<Option<ControlClient>, AppError> {
+  let (
+    Some(addr),
+    Commands::SyscallSendrecv {
+      server_addr,
+      nb_sockets,
+      one_way,
+      ..
+    },
+  ) = (&cli.control_addr, &cli.command)
+  else {
+    return Ok(None);
+  };
+  let params = TestParams {
+    version: control::PROTOCOL_VERSION,
+    mode: if *one_way { TestMode::Sink } else { TestMode::Echo },
+    data_port: control::port_of(server_addr)?,
+    packet_size: cli.packet_size,
+    seed: cli.seed,
+    packet_format: cli.packet_format,
+    nb_sockets: *nb_sockets,
+    start_delay_ms: cli.start_delay_ms,
+  };
+  ControlClient::start(addr, params).map(Some)
+}
+
+/// Listen for control connections, if this is a server and --control-addr was
+/// given.
+fn bind_control_server(cli: &Cli) -> Result<Option<ControlServer>, AppError> {
+  let (
+    Some(addr),
+    Commands::SyscallEcho { server_addr, mtu, .. }
+    | Commands::IoUringEcho { server_addr, mtu, .. }
+    | Commands::Sink { server_addr, mtu, .. },
+  ) = (&cli.control_addr, &cli.command)
+  else {
+    return Ok(None);
+  };
+  let info = ServerInfo {
+    backend: cli.command.name(),
+    mode: match cli.command {
+      Commands::Sink { .. } => TestMode::Sink,
+      _ => TestMode::Echo,
+    },
+    data_port: control::port_of(server_addr)?,
+    mtu: *mtu,
+    packet_format: cli.packet_format,
+  };
+  ControlServer::bind(addr, info).map(Some)
+}
+
+/// Reject combinations of arguments that clap can't check, before setting
+/// anything up.
+fn check_args(cli: &Cli) -> Result<(), AppError> {
+  if let Commands::Sink { .. } = cli.command {
+    if cli.packet_format == PacketFormat::Twamp {
+      return Err(AppError::NotImplemented("receiving TWAMP packets in a sink"));
+    }
+  }
+  // Network destinations have no extension to guess from, and collectors
+  // don't take CSV.
+  if let (Some(stats_file), None) = (&cli.stats_file, cli.stats_format) {
+    let dest = stats_file.to_str().unwrap_or("");
+    if dest.starts_with("udp://") || dest.starts_with("tcp://") {
+      Cli::command()
+        .error(
+          ErrorKind::MissingRequiredArgument,
+          "--stats-format is required for udp:// and tcp:// stats destinations",
+        )
+        .exit();
+    }
+  }
+  // Not a clap conflict, since --dump-config writes out the defaults of the
+  // other two, and the config would then conflict with itself.
+  if let Commands::SyscallSendrecv {
+    batch_size,
+    one_way,
+    window,
+    window_timeout_ms,
+    ..
+  } = cli.command
+  {
+    if window.is_some() && (batch_size > 1 || one_way) {
+      Cli::command()
+        .error(
+          ErrorKind::ArgumentConflict,
+          "--window can't be used with --batch-size above 1 or --one-way",
+        )
+        .exit();
+    }
+    if window.is_none() && window_timeout_ms.is_some() {
+      Cli::command()
+        .error(
+          ErrorKind::MissingRequiredArgument,
+          "--window-timeout-ms requires --window",
+        )
+        .exit();
+    }
+  }
+  Ok(())
 }
 
 fn run() -> Result<(), AppError> {
-  let cli = Cli::parse();
-  let stats = make_stats_aggregator_from_arg(&cli)?;
+  let cli: Cli = config::parse()?;
+  if cli.command.is_offline() {
+    return run_offline(&cli);
+  }
+  check_args(&cli)?;
+  let run_info = RunInfo {
+    mode: cli.command.name(),
+    nb_sockets: cli.command.nb_sockets(),
+    per_socket: cli.stats_per_socket,
+    step_size: get_time_value_from_duration(Duration::from_millis(cli.stats_interval_ms)),
+    start_wallclock: SystemTime::now(),
+  };
+  let shutdown = Shutdown::install(Duration::from_millis(cli.drain_ms))?;
+  let control_server = bind_control_server(&cli)?;
+  // Agree on the test with the server before starting the clock, so that the
+  // handshake and start delay are not counted as part of the run.
+  let control_client = start_control_client(&cli)?;
+  let start_time = Instant::now();
+  let summary = RunSummary::default();
+  let stats = make_stats_aggregator_from_arg(&cli, &run_info, &summary)?;
+  let stopped = AtomicBool::new(false);
+  let res = thread::scope(|scope| {
+    // Keep writing out steps even if no packets are flowing.
+    scope.spawn(|| stats.run_ticker(start_time, &stopped));
+    let control_thread = control_server.as_ref().map(|c| scope.spawn(|| c.run(&stopped)));
+    let peers = control_server.as_ref().map(|c| c.peers());
+    let res = run_command(&cli, &stats, start_time, peers, shutdown);
+    stopped.store(true, Ordering::Relaxed);
+    let control_res = control_thread.map_or(Ok(()), |t| t.join().unwrap());
+    res.and(control_res)
+  });
+  let control_res = control_client
+    .map(|c| c.finish(&stats.socket_local_ports()))
+    .transpose();
+  stats.flush(get_time_value_now(start_time));
+  // Dropping the sinks flushes their output.
+  drop(stats);
+  let elapsed = start_time.elapsed();
+  let server_counts = control_res.as_ref().ok().and_then(Option::as_ref);
+  summary.print(elapsed, server_counts);
+  if let Some(summary_file) = &cli.summary_file {
+    summary.write_file(summary_file, elapsed, server_counts)?;
+  }
+  res.and(control_res.map(|_| ()))
+}
+
+/// What echo servers should do to the packets they send back.
+fn reflect_mode(cli: &Cli, reflect_timestamps: bool) -> ReflectMode {
+  match cli.packet_format {
+    PacketFormat::Twamp => ReflectMode::Twamp,
+    PacketFormat::Neuring if reflect_timestamps => ReflectMode::Timestamps,
+    PacketFormat::Neuring => ReflectMode::Unchanged,
+  }
+}
+
+fn run_command(
+  cli: &Cli,
+  stats: &StatsAggregator,
+  start_time: Instant,
+  peers: Option<&PeerTable>,
+  shutdown: Shutdown,
+) -> Result<(), AppError> {
   match cli.command {
+    Commands::Analyze { .. } | Commands::Compare { .. } | Commands::Plot { .. } => {
+      unreachable!()
+    }
     Commands::SyscallSendrecv {
       ref server_addr,
       batch_size,
       nb_sockets,
+      ref cpus,
+      one_way,
+      window,
+      window_timeout_ms,
     } => io_impl::syscall_sendrecv::syscall_sendrecv(
       server_addr,
-      cli.packet_size as usize,
-      batch_size,
-      cli.seed,
-      nb_sockets,
-      &stats,
-      Instant::now(),
+      &io_impl::syscall_sendrecv::SendOptions {
+        packet_size: cli.packet_size as usize,
+        batch_size,
+        seed: cli.seed,
+        packet_format: cli.packet_format,
+        one_way,
+        window,
+        window_timeout: Duration::from_millis(window_timeout_ms.unwrap_or(1000)),
+        nb_sockets,
+      },
+      stats,
+      start_time,
+      cpus.as_ref(),
+      shutdown,
     ),
     Commands::SyscallEcho {
       ref server_addr,
       nb_sockets,
       mtu,
-    } => io_impl::syscall_echo::syscall_echo(server_addr, mtu, nb_sockets, Instant::now(), &stats),
+      ref cpus,
+      reflect_timestamps,
+    } => io_impl::syscall_echo::syscall_echo(
+      server_addr,
+      mtu,
+      nb_sockets,
+      start_time,
+      stats,
+      cpus.as_ref(),
+      reflect_mode(cli, reflect_timestamps),
+      peers,
+      shutdown,
+    ),
+    Commands::Sink {
+      ref server_addr,
+      nb_sockets,
+      mtu,
+      ref cpus,
+    } => io_impl::syscall_sink::syscall_sink(
+      server_addr,
+      mtu,
+      cli.seed,
+      nb_sockets,
+      start_time,
+      stats,
+      cpus.as_ref(),
+      peers,
+      shutdown,
+    ),
     Commands::IoUringEcho {
       ref server_addr,
       nb_sockets,
@@ -178,15 +798,19 @@ fn run() -> Result<(), AppError> {
       ring_size,
       kernel_poll_timeout,
       nb_recv,
+      reflect_timestamps,
     } => io_impl::iouring_echo::iouring_echo(
       server_addr,
       mtu,
       nb_sockets,
-      Instant::now(),
-      &stats,
+      start_time,
+      stats,
       ring_size,
       nb_recv,
       kernel_poll_timeout,
+      reflect_mode(cli, reflect_timestamps),
+      peers,
+      shutdown,
     ),
   }
 }
@@ -6,9 +6,41 @@
 //! generate paddings that can be used to fill up packets to a certain size, and
 //! validated on the receiving end.
 //!
-//! Time values provided to this module can be in any unit.
+//! Time values provided to this module can be in any unit, except for the
+//! clock fields of the header, which are compared across machines and are
+//! always from [`clock_now`].
 
+use std::mem::offset_of;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::twamp;
+
+/// The layout of the packets we send and expect back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketFormat {
+  /// Our own header, with padding derived from the seed that is checked on
+  /// receipt
+  #[default]
+  Neuring,
+  /// TWAMP-Light test packets (RFC 5357, unauthenticated mode)
+  Twamp,
+}
+
+/// What echo servers do to packets before sending them back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReflectMode {
+  /// Send them back as they came.
+  Unchanged,
+  /// Stamp our own header with reflector timestamps, see [`reflect_packet`].
+  Timestamps,
+  /// Turn TWAMP-Light test packets into reflected ones.
+  Twamp,
+}
 
 /// The header which appears on every packet, which contains useful metadata which
 /// aids statistics.
@@ -16,10 +48,26 @@ use rand::RngCore;
 pub struct PacketHeader {
   pub index: u64,
   pub send_time: u64,
+
+  /// Wall clock time the packet was sent at.
+  pub send_clock: u64,
+
+  /// Wall clock times an echo server in reflector mode received the packet
+  /// and sent it back at, or 0 if it didn't fill them in.  These are not
+  /// covered by the padding check.
+  pub reflect_recv_clock: u64,
+  pub reflect_send_clock: u64,
 }
 
 pub const PACKET_HEAD_SIZE: usize = std::mem::size_of::<PacketHeader>();
 
+/// The wall clock, in nanoseconds since the Unix epoch.
+pub fn clock_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_nanos() as u64)
+}
+
 /// Write a packet to the given buffer.
 ///
 /// The size of the packet will be determined by the size of the buffer. This
@@ -27,10 +75,16 @@ pub const PACKET_HEAD_SIZE: usize = std::mem::size_of::<PacketHeader>();
 ///
 /// The provided seed will be used to generate packet padding, and the same
 /// value should be provided to parse_packet.
-pub fn write_packet(seed: u64, index: u64, send_time: u64, buf: &mut [u8]) {
+pub fn write_packet(seed: u64, index: u64, send_time: u64, send_clock: u64, buf: &mut [u8]) {
   debug_assert!(buf.len() >= PACKET_HEAD_SIZE);
 
-  let ph = PacketHeader { index, send_time };
+  let ph = PacketHeader {
+    index,
+    send_time,
+    send_clock,
+    reflect_recv_clock: 0,
+    reflect_send_clock: 0,
+  };
 
   // Put the header at the beginning of the buffer.
   //
@@ -84,3 +138,67 @@ pub fn parse_packet(seed: u64, buf: &[u8]) -> Result<PacketHeader, ()> {
 
   Ok(ph)
 }
+
+/// Stamp a packet about to be echoed with the times it was received and sent
+/// back at.  Packets too short to hold a header are left alone.
+pub fn reflect_packet(buf: &mut [u8], recv_clock: u64, send_clock: u64) {
+  if buf.len() < PACKET_HEAD_SIZE {
+    return;
+  }
+
+  // Safety:
+  //  - The buffer is at least PACKET_HEAD_SIZE bytes long.
+  //  - We use write_unaligned to avoid alignment issues.
+  unsafe {
+    let head = buf.as_mut_ptr();
+    std::ptr::write_unaligned(
+      head.add(offset_of!(PacketHeader, reflect_recv_clock)) as *mut u64,
+      recv_clock,
+    );
+    std::ptr::write_unaligned(
+      head.add(offset_of!(PacketHeader, reflect_send_clock)) as *mut u64,
+      send_clock,
+    );
+  }
+}
+
+/// The state of one echo socket for reflecting packets.
+pub struct Reflector {
+  mode: ReflectMode,
+
+  /// The sequence number of the next reflected TWAMP packet.  These count the
+  /// packets of the socket, not of each sender.
+  seq: u32,
+}
+
+impl Reflector {
+  pub fn new(mode: ReflectMode) -> Self {
+    Self { mode, seq: 0 }
+  }
+
+  /// Whether [`Self::reflect`] needs the receive time, so that it can be
+  /// skipped otherwise.
+  pub fn needs_clock(&self) -> bool {
+    self.mode != ReflectMode::Unchanged
+  }
+
+  /// Prepare the packet of `len` bytes received at the start of `buf` to be
+  /// sent back, and return how many bytes to send.  `recv_clock` is when it
+  /// was received, and `ttl` the TTL or hop limit it arrived with, if known.
+  pub fn reflect(&mut self, buf: &mut [u8], len: usize, recv_clock: u64, ttl: Option<u8>) -> usize {
+    match self.mode {
+      ReflectMode::Unchanged => len,
+      ReflectMode::Timestamps => {
+        reflect_packet(&mut buf[..len], recv_clock, clock_now());
+        len
+      }
+      ReflectMode::Twamp => {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        // Without the TTL, pretend the packet came straight from the sender.
+        let ttl = ttl.unwrap_or(twamp::SENDER_TTL);
+        twamp::reflect(buf, len, seq, recv_clock, clock_now(), ttl)
+      }
+    }
+  }
+}
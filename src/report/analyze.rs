@@ -0,0 +1,208 @@
+//! The `analyze` subcommand, summarizing a stats file and pointing out loss
+//! bursts and latency spikes.
+
+use std::sync::atomic::Ordering;
+
+use crate::report::{format_percentile, median, StatsRecording, StepMetrics};
+use crate::stats::{duration_from_time_value, max_latency_bound_ms, LATENCY_BUCKET_BOUNDS_US};
+
+/// Settings for [`analyze`].
+#[derive(Debug, Clone)]
+pub struct AnalyzeOptions {
+  /// Number of worst steps to list.
+  pub top: usize,
+
+  /// A step is part of a loss burst if its drop rate exceeds the median drop
+  /// rate by more than this fraction.
+  pub loss_burst_threshold: f64,
+
+  /// A step is part of a latency spike if its average latency is more than
+  /// this many times the median.
+  pub latency_spike_factor: f64,
+}
+
+/// A run of consecutive steps matching some condition.
+struct Episode {
+  start: usize,
+  end: usize,
+}
+
+/// Find runs of consecutive steps for which `cond` holds.
+fn find_episodes(metrics: &[StepMetrics], cond: impl Fn(&StepMetrics) -> bool) -> Vec<Episode> {
+  let mut episodes = Vec::new();
+  let mut current: Option<Episode> = None;
+  for (i, m) in metrics.iter().enumerate() {
+    if cond(m) {
+      match current.as_mut() {
+        Some(e) => e.end = i,
+        None => current = Some(Episode { start: i, end: i }),
+      }
+    } else if let Some(e) = current.take() {
+      episodes.push(e);
+    }
+  }
+  episodes.extend(current);
+  episodes
+}
+
+/// Print a summary of the recording to stdout.
+pub fn analyze(rec: &StatsRecording, opts: &AnalyzeOptions) {
+  let step_secs = duration_from_time_value(rec.step_size).as_secs_f64();
+  let metrics: Vec<StepMetrics> = rec
+    .steps
+    .iter()
+    .map(|(t, s)| StepMetrics::new(*t, s, rec.step_size))
+    .collect();
+  let total = rec.total();
+  let load = |c: &std::sync::atomic::AtomicU64| c.load(Ordering::Acquire);
+
+  let first = metrics.first().unwrap().secs;
+  let last = metrics.last().unwrap().secs + step_secs;
+  println!(
+    "{} steps of {:.0}ms, {first:.1}s to {last:.1}s",
+    metrics.len(),
+    step_secs * 1000.0
+  );
+
+  // Steady state is taken as the median over steps where anything happened,
+  // so that ramp-up, idle periods and the partial last step don't skew it.
+  let active: Vec<&StepMetrics> = metrics.iter().filter(|m| m.tx_pps > 0.0 || m.rx_pps > 0.0).collect();
+  println!();
+  println!("Throughput ({} active steps):", active.len());
+  println!("        {:>12} {:>12} {:>12} {:>12}", "median", "mean", "min", "max");
+  let rate_line = |name: &str, unit: &str, f: &dyn Fn(&StepMetrics) -> f64| {
+    let values: Vec<f64> = active.iter().map(|m| f(m)).collect();
+    if values.is_empty() {
+      return;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    println!(
+      "  {name:<6}{:>12.1} {mean:>12.1} {min:>12.1} {max:>12.1} {unit}",
+      median(&values).unwrap()
+    );
+  };
+  rate_line("tx", "pps", &|m| m.tx_pps);
+  rate_line("rx", "pps", &|m| m.rx_pps);
+  rate_line("tx", "Mbit/s", &|m| m.tx_mbps);
+  rate_line("rx", "Mbit/s", &|m| m.rx_mbps);
+
+  let tx_packets = load(&total.tx_packets);
+  let rx_sent_here = load(&total.rx_packets_sent_here);
+  let late = load(&total.rx_late_packets);
+  if rx_sent_here == 0 {
+    println!();
+    println!("No echoes recorded, so no loss or latency (is this from an echo server?).");
+    return;
+  }
+
+  let lost = tx_packets.saturating_sub(rx_sent_here + late);
+  println!();
+  println!(
+    "Loss: sent {tx_packets}, echoed {rx_sent_here}, late {late}, lost {lost} ({:.3}%)",
+    lost as f64 * 100.0 / tx_packets as f64
+  );
+  println!(
+    "  sock drops {}, udp rcvbuf errors {}, send errors {}",
+    load(&total.rx_sock_drops),
+    load(&total.udp_rcvbuf_errors),
+    load(&total.tx_err_eagain)
+      + load(&total.tx_err_enobufs)
+      + load(&total.tx_err_econnrefused)
+      + load(&total.tx_err_other)
+  );
+
+  println!();
+  println!(
+    "Latency (ms): avg {:.3}, p50 {}, p90 {}, p99 {}, p99.9 {}",
+    total.avg_latency().unwrap(),
+    format_percentile(total.latency_percentile(0.5)),
+    format_percentile(total.latency_percentile(0.9)),
+    format_percentile(total.latency_percentile(0.99)),
+    format_percentile(total.latency_percentile(0.999)),
+  );
+  let hist_total: u64 = total.latency_hist.iter().map(load).sum();
+  for (i, count) in total.latency_hist.iter().enumerate() {
+    let count = load(count);
+    if count == 0 {
+      continue;
+    }
+    let label = match LATENCY_BUCKET_BOUNDS_US.get(i) {
+      Some(&bound) => format!("<={}", bound as f64 / 1e3),
+      None => format!(">{}", max_latency_bound_ms()),
+    };
+    let fraction = count as f64 / hist_total as f64;
+    println!(
+      "  {label:>7} {count:>12} {:>7.3}% {}",
+      fraction * 100.0,
+      "#".repeat((fraction * 50.0).round() as usize)
+    );
+  }
+
+  let sending: Vec<&StepMetrics> = metrics.iter().filter(|m| m.tx_packets > 0).collect();
+  let mut by_drop = sending.clone();
+  by_drop.sort_by(|a, b| b.drop_rate.unwrap().total_cmp(&a.drop_rate.unwrap()));
+  println!();
+  println!("Worst steps by loss:");
+  for m in by_drop.iter().take(opts.top) {
+    println!(
+      "  t={:>8.1}s drop {:>7.3}% ({} of {})",
+      m.secs,
+      m.drop_rate.unwrap() * 100.0,
+      m.lost,
+      m.tx_packets
+    );
+  }
+  let mut by_latency: Vec<&StepMetrics> = sending.iter().filter(|m| m.avg_latency.is_some()).cloned().collect();
+  by_latency.sort_by(|a, b| b.avg_latency.unwrap().total_cmp(&a.avg_latency.unwrap()));
+  println!("Worst steps by latency:");
+  for m in by_latency.iter().take(opts.top) {
+    println!("  t={:>8.1}s avg {:>9.2}ms", m.secs, m.avg_latency.unwrap());
+  }
+
+  let drop_rates: Vec<f64> = sending.iter().filter_map(|m| m.drop_rate).collect();
+  let latencies: Vec<f64> = sending.iter().filter_map(|m| m.avg_latency).collect();
+  let median_drop = median(&drop_rates).unwrap_or(0.0);
+  let median_latency = median(&latencies).unwrap_or(0.0);
+  let bursts = find_episodes(&metrics, |m| {
+    m.drop_rate.map_or(false, |d| d > median_drop + opts.loss_burst_threshold)
+  });
+  // Also require a difference of at least one time unit, so that tiny
+  // latencies don't make every bit of jitter a spike.
+  let spikes = find_episodes(&metrics, |m| {
+    m.avg_latency.map_or(false, |l| {
+      l > median_latency * opts.latency_spike_factor && l > median_latency + 1.0
+    })
+  });
+  println!();
+  println!(
+    "Anomalies (median drop {:.3}%, median latency {median_latency:.2}ms):",
+    median_drop * 100.0
+  );
+  if bursts.is_empty() && spikes.is_empty() {
+    println!("  none detected");
+  }
+  for e in bursts.iter() {
+    let steps = &metrics[e.start..=e.end];
+    let max_drop = steps.iter().filter_map(|m| m.drop_rate).fold(0.0, f64::max);
+    let lost: u64 = steps.iter().map(|m| m.lost).sum();
+    println!(
+      "  loss burst    {:>8.1}s - {:>8.1}s ({} steps): drop up to {:.3}%, {lost} lost",
+      steps[0].secs,
+      steps[steps.len() - 1].secs + step_secs,
+      steps.len(),
+      max_drop * 100.0
+    );
+  }
+  for e in spikes.iter() {
+    let steps = &metrics[e.start..=e.end];
+    let max_latency = steps.iter().filter_map(|m| m.avg_latency).fold(0.0, f64::max);
+    println!(
+      "  latency spike {:>8.1}s - {:>8.1}s ({} steps): avg up to {max_latency:.2}ms",
+      steps[0].secs,
+      steps[steps.len() - 1].secs + step_secs,
+      steps.len()
+    );
+  }
+}
@@ -0,0 +1,315 @@
+//! The `compare` subcommand, an A/B check between a baseline and a candidate
+//! run.
+//!
+//! Significance is tested with Welch's t-test over the per-step values for
+//! throughput and average latency, and with a two-proportion z-test over the
+//! packet counts for loss.  Both use the normal approximation, which is fine
+//! with the dozens of steps a run usually has, but optimistic with only a few.
+//! Latency percentiles come from the histogram, so there is no test for them.
+
+use std::sync::atomic::Ordering;
+
+use crate::report::{format_percentile, StatsRecording, StepMetrics};
+use crate::stats::Stats;
+
+/// Settings for [`compare`].
+#[derive(Debug, Clone)]
+pub struct CompareOptions {
+  /// Fail if tx or rx packets per second drop by more than this percentage.
+  pub max_throughput_drop: Option<f64>,
+
+  /// Fail if loss increases by more than this many percentage points.
+  pub max_loss_increase: Option<f64>,
+
+  /// Fail if the average or p99 latency increases by more than this
+  /// percentage.
+  pub max_latency_increase: Option<f64>,
+
+  /// p-value under which a change is considered significant.
+  pub alpha: f64,
+
+  /// Only fail on changes that are significant.  Rows without a test, like
+  /// percentiles, are always checked.
+  pub significant_only: bool,
+}
+
+/// Which threshold applies to a row.
+#[derive(Clone, Copy, PartialEq)]
+enum Check {
+  None,
+  Throughput,
+  Loss,
+  Latency,
+}
+
+/// A per-step value to compare.
+type StepValue = fn(&StepMetrics) -> f64;
+
+struct Row {
+  name: &'static str,
+  baseline: f64,
+  candidate: f64,
+  p_value: Option<f64>,
+  check: Check,
+
+  /// Whether the values are histogram bucket bounds, with infinity for the
+  /// overflow bucket.
+  percentile: bool,
+}
+
+impl Row {
+  fn delta(&self) -> f64 {
+    self.candidate - self.baseline
+  }
+
+  fn relative_delta(&self) -> Option<f64> {
+    if self.baseline == self.candidate {
+      Some(0.0)
+    } else if self.baseline != 0.0 && self.baseline.is_finite() {
+      Some(self.delta() / self.baseline * 100.0)
+    } else {
+      None
+    }
+  }
+
+  fn format_value(&self, value: f64) -> String {
+    if self.percentile {
+      format_percentile(Some(value))
+    } else {
+      format!("{value:.2}")
+    }
+  }
+
+  /// Whether this row's change goes over its threshold.
+  fn exceeds(&self, opts: &CompareOptions) -> bool {
+    if opts.significant_only && self.p_value.is_some_and(|p| p >= opts.alpha) {
+      return false;
+    }
+    let relative_increase = self.relative_delta().unwrap_or(f64::INFINITY * self.delta().signum());
+    match self.check {
+      Check::None => false,
+      Check::Throughput => opts.max_throughput_drop.is_some_and(|max| -relative_increase > max),
+      Check::Loss => opts.max_loss_increase.is_some_and(|max| self.delta() > max),
+      Check::Latency => opts.max_latency_increase.is_some_and(|max| relative_increase > max),
+    }
+  }
+}
+
+/// Complementary error function, from Abramowitz and Stegun 7.1.26.  The
+/// absolute error is below 1.5e-7, which is plenty for p-values.
+fn erfc(x: f64) -> f64 {
+  let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+  let poly = t
+    * (0.254829592
+      + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+  let r = poly * (-x * x).exp();
+  if x >= 0.0 {
+    r
+  } else {
+    2.0 - r
+  }
+}
+
+/// Two-sided p-value of a standard normal statistic.
+fn p_value_from_z(z: f64) -> f64 {
+  erfc(z.abs() / std::f64::consts::SQRT_2)
+}
+
+fn mean_and_variance(values: &[f64]) -> (f64, f64) {
+  let n = values.len() as f64;
+  let mean = values.iter().sum::<f64>() / n;
+  let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+  (mean, variance)
+}
+
+/// p-value of Welch's t-test between two samples, or `None` if either has
+/// fewer than 2 values.
+fn welch_test(a: &[f64], b: &[f64]) -> Option<f64> {
+  if a.len() < 2 || b.len() < 2 {
+    return None;
+  }
+  let (mean_a, var_a) = mean_and_variance(a);
+  let (mean_b, var_b) = mean_and_variance(b);
+  let se = (var_a / a.len() as f64 + var_b / b.len() as f64).sqrt();
+  if se == 0.0 {
+    return Some(if mean_a == mean_b { 1.0 } else { 0.0 });
+  }
+  Some(p_value_from_z((mean_b - mean_a) / se))
+}
+
+/// p-value of a two-proportion z-test between `x_a` out of `n_a` and `x_b`
+/// out of `n_b`.
+fn proportion_test(x_a: u64, n_a: u64, x_b: u64, n_b: u64) -> Option<f64> {
+  if n_a == 0 || n_b == 0 {
+    return None;
+  }
+  let (n_a, n_b) = (n_a as f64, n_b as f64);
+  let pooled = (x_a + x_b) as f64 / (n_a + n_b);
+  let se = (pooled * (1.0 - pooled) * (1.0 / n_a + 1.0 / n_b)).sqrt();
+  if se == 0.0 {
+    return Some(1.0);
+  }
+  Some(p_value_from_z((x_b as f64 / n_b - x_a as f64 / n_a) / se))
+}
+
+/// What gets compared from each recording.
+struct Side {
+  metrics: Vec<StepMetrics>,
+  total: Stats,
+}
+
+impl Side {
+  fn new(rec: &StatsRecording) -> Self {
+    Self {
+      metrics: rec
+        .steps
+        .iter()
+        .map(|(t, s)| StepMetrics::new(*t, s, rec.step_size))
+        .collect(),
+      total: rec.total(),
+    }
+  }
+
+  fn load(&self, f: impl Fn(&Stats) -> &std::sync::atomic::AtomicU64) -> u64 {
+    f(&self.total).load(Ordering::Acquire)
+  }
+
+  /// Values of steps where anything happened, like the steady state of
+  /// `analyze`.
+  fn active(&self, f: impl Fn(&StepMetrics) -> f64) -> Vec<f64> {
+    self
+      .metrics
+      .iter()
+      .filter(|m| m.tx_pps > 0.0 || m.rx_pps > 0.0)
+      .map(f)
+      .collect()
+  }
+
+  fn echoed(&self) -> u64 {
+    self.load(|s| &s.rx_packets_sent_here)
+  }
+
+  fn lost(&self) -> u64 {
+    self
+      .load(|s| &s.tx_packets)
+      .saturating_sub(self.echoed() + self.load(|s| &s.rx_late_packets))
+  }
+}
+
+fn mean(values: &[f64]) -> f64 {
+  if values.is_empty() {
+    0.0
+  } else {
+    values.iter().sum::<f64>() / values.len() as f64
+  }
+}
+
+/// Print the differences between two recordings to stdout, and return how
+/// many thresholds were exceeded.
+pub fn compare(
+  baseline: &StatsRecording,
+  candidate: &StatsRecording,
+  opts: &CompareOptions,
+) -> usize {
+  let a = Side::new(baseline);
+  let b = Side::new(candidate);
+  let mut rows = Vec::new();
+
+  let rates: [(&str, StepValue, Check); 4] = [
+    ("tx pps", |m| m.tx_pps, Check::Throughput),
+    ("rx pps", |m| m.rx_pps, Check::Throughput),
+    ("tx Mbit/s", |m| m.tx_mbps, Check::None),
+    ("rx Mbit/s", |m| m.rx_mbps, Check::None),
+  ];
+  for (name, f, check) in rates {
+    let (values_a, values_b) = (a.active(f), b.active(f));
+    rows.push(Row {
+      name,
+      baseline: mean(&values_a),
+      candidate: mean(&values_b),
+      p_value: welch_test(&values_a, &values_b),
+      check,
+      percentile: false,
+    });
+  }
+
+  if a.echoed() > 0 && b.echoed() > 0 {
+    let (tx_a, tx_b) = (a.load(|s| &s.tx_packets), b.load(|s| &s.tx_packets));
+    rows.push(Row {
+      name: "loss %",
+      baseline: a.lost() as f64 * 100.0 / tx_a as f64,
+      candidate: b.lost() as f64 * 100.0 / tx_b as f64,
+      p_value: proportion_test(a.lost(), tx_a, b.lost(), tx_b),
+      check: Check::Loss,
+      percentile: false,
+    });
+
+    let step_latencies = |side: &Side| -> Vec<f64> {
+      side.metrics.iter().filter_map(|m| m.avg_latency).collect()
+    };
+    let avg_latency = |side: &Side| side.total.avg_latency().unwrap();
+    rows.push(Row {
+      name: "latency avg ms",
+      baseline: avg_latency(&a),
+      candidate: avg_latency(&b),
+      p_value: welch_test(&step_latencies(&a), &step_latencies(&b)),
+      check: Check::Latency,
+      percentile: false,
+    });
+
+    let percentiles: [(&str, f64, Check); 4] = [
+      ("latency p50 ms", 0.5, Check::None),
+      ("latency p90 ms", 0.9, Check::None),
+      ("latency p99 ms", 0.99, Check::Latency),
+      ("latency p99.9 ms", 0.999, Check::None),
+    ];
+    let percentile = |side: &Side, q| side.total.latency_percentile(q).unwrap_or(f64::INFINITY);
+    for (name, q, check) in percentiles {
+      rows.push(Row {
+        name,
+        baseline: percentile(&a, q),
+        candidate: percentile(&b, q),
+        p_value: None,
+        check,
+        percentile: true,
+      });
+    }
+  } else {
+    println!("No echoes recorded in one of the runs, only comparing throughput.");
+    println!();
+  }
+
+  println!(
+    "{:<18} {:>12} {:>12} {:>12} {:>9} {:>8}",
+    "", "baseline", "candidate", "delta", "delta %", "p"
+  );
+  let mut exceeded = 0;
+  for row in rows.iter() {
+    let delta = if row.percentile && !(row.baseline.is_finite() && row.candidate.is_finite()) {
+      "-".to_owned()
+    } else {
+      format!("{:+.2}", row.delta())
+    };
+    let relative = match row.relative_delta() {
+      Some(r) => format!("{r:+.1}%"),
+      None => "-".to_owned(),
+    };
+    let (p, significant) = match row.p_value {
+      Some(p) => (format!("{p:.3}"), p < opts.alpha),
+      None => ("-".to_owned(), false),
+    };
+    let fail = row.exceeds(opts);
+    exceeded += fail as usize;
+    println!(
+      "{:<18} {:>12} {:>12} {delta:>12} {relative:>9} {p:>8}{}{}",
+      row.name,
+      row.format_value(row.baseline),
+      row.format_value(row.candidate),
+      if significant { " *" } else { "" },
+      if fail { "  FAIL" } else { "" },
+    );
+  }
+  println!();
+  println!("* significant at p < {}", opts.alpha);
+  exceeded
+}
@@ -0,0 +1,89 @@
+//! Offline processing of stats files written by the stats sinks.
+
+use std::sync::atomic::Ordering;
+
+use crate::stats::{duration_from_time_value, max_latency_bound_ms, Stats};
+
+mod reader;
+pub use reader::*;
+
+mod analyze;
+pub use analyze::*;
+
+mod compare;
+pub use compare::*;
+
+mod plot;
+pub use plot::*;
+
+/// Values of a single step that reports look at, derived from its counters.
+#[derive(Debug, Clone, Copy)]
+pub struct StepMetrics {
+  /// Start of the step, in seconds.
+  pub secs: f64,
+
+  pub tx_pps: f64,
+  pub rx_pps: f64,
+  pub tx_mbps: f64,
+  pub rx_mbps: f64,
+
+  /// Packets sent in this step.
+  pub tx_packets: u64,
+
+  /// Packets sent in this step that did not come back before the step was
+  /// written out.
+  pub lost: u64,
+
+  /// Fraction of packets sent in this step that did not come back, or `None`
+  /// if nothing was sent.  Only meaningful for the sender.
+  pub drop_rate: Option<f64>,
+
+  /// Average latency of packets sent in this step, or `None` if none came
+  /// back.
+  pub avg_latency: Option<f64>,
+}
+
+impl StepMetrics {
+  pub fn new(time: u64, stats: &Stats, step_size: u64) -> Self {
+    let load = |c: &std::sync::atomic::AtomicU64| c.load(Ordering::Acquire);
+    let step_secs = duration_from_time_value(step_size).as_secs_f64();
+    let tx_packets = load(&stats.tx_packets);
+    let rx_sent_here = load(&stats.rx_packets_sent_here);
+    Self {
+      secs: duration_from_time_value(time).as_secs_f64(),
+      tx_pps: tx_packets as f64 / step_secs,
+      rx_pps: load(&stats.rx_packets) as f64 / step_secs,
+      tx_mbps: load(&stats.tx_bytes) as f64 * 8.0 / 1e6 / step_secs,
+      rx_mbps: load(&stats.rx_bytes) as f64 * 8.0 / 1e6 / step_secs,
+      tx_packets,
+      lost: tx_packets.saturating_sub(rx_sent_here),
+      drop_rate: (tx_packets > 0).then(|| 1.0 - rx_sent_here as f64 / tx_packets as f64),
+      avg_latency: stats.avg_latency(),
+    }
+  }
+}
+
+/// Format a latency percentile from [`Stats::latency_percentile`].
+pub fn format_percentile(value: Option<f64>) -> String {
+  match value {
+    None => "-".to_owned(),
+    Some(v) if v.is_infinite() => format!(">{}", max_latency_bound_ms()),
+    Some(v) => format!("<={}", v),
+  }
+}
+
+/// Median of some values.  NaN, which stats files may contain, sorts after
+/// everything else.
+pub fn median(values: &[f64]) -> Option<f64> {
+  if values.is_empty() {
+    return None;
+  }
+  let mut sorted = values.to_vec();
+  sorted.sort_by(f64::total_cmp);
+  let mid = sorted.len() / 2;
+  Some(if sorted.len() % 2 == 0 {
+    (sorted[mid - 1] + sorted[mid]) / 2.0
+  } else {
+    sorted[mid]
+  })
+}
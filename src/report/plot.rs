@@ -0,0 +1,228 @@
+//! The `plot` subcommand, rendering a stats file as a self-contained SVG with
+//! stacked time-series panels sharing the same time axis.
+
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::report::{StatsRecording, StepMetrics};
+use crate::stats::{duration_from_time_value, max_latency_bound_ms};
+
+const PANEL_HEIGHT: f64 = 180.0;
+const PANEL_GAP: f64 = 70.0;
+const MARGIN_TOP: f64 = 50.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+
+struct Series {
+  name: &'static str,
+  color: &'static str,
+
+  /// One value per step, `None` for gaps.
+  values: Vec<Option<f64>>,
+}
+
+struct Panel {
+  title: &'static str,
+  series: Vec<Series>,
+}
+
+/// Pick a round tick interval, so that about `target` ticks fit in `range`.
+fn tick_step(range: f64, target: f64) -> f64 {
+  let raw = range / target;
+  let magnitude = 10f64.powf(raw.log10().floor());
+  let step = [1.0, 2.0, 5.0, 10.0]
+    .into_iter()
+    .find(|m| m * magnitude >= raw)
+    .unwrap();
+  step * magnitude
+}
+
+/// Format an axis label, shortening large values with k and M.
+fn format_tick(value: f64) -> String {
+  let (value, suffix) = if value >= 1e6 {
+    (value / 1e6, "M")
+  } else if value >= 1e3 {
+    (value / 1e3, "k")
+  } else {
+    (value, "")
+  };
+  let s = format!("{value:.2}");
+  format!("{}{suffix}", s.trim_end_matches('0').trim_end_matches('.'))
+}
+
+fn escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Build the panels from the steps of the recording.
+fn panels(rec: &StatsRecording, metrics: &[StepMetrics]) -> Vec<Panel> {
+  // Non-finite values can't be drawn, so leave gaps for them.
+  let series = |name, color, f: &dyn Fn(usize) -> Option<f64>| Series {
+    name,
+    color,
+    values: (0..metrics.len()).map(|i| f(i).filter(|v| v.is_finite())).collect(),
+  };
+  // The overflow bucket has no bound, so show it at the last one.
+  let percentile = |i: usize, q| {
+    rec.steps[i]
+      .1
+      .latency_percentile(q)
+      .map(|v| v.min(max_latency_bound_ms()))
+  };
+  let mut panels = vec![Panel {
+    title: "Throughput (packets/s)",
+    series: vec![
+      series("tx", "#1f77b4", &|i| Some(metrics[i].tx_pps)),
+      series("rx", "#ff7f0e", &|i| Some(metrics[i].rx_pps)),
+    ],
+  }];
+  // Echo servers record no echoes of their own, so they have no loss or
+  // latency to show, and the drop rate would be a flat 100%.
+  let echoed = rec.steps.iter().any(|(_, s)| s.rx_packets_sent_here.load(Ordering::Acquire) > 0);
+  if !echoed {
+    return panels;
+  }
+  panels.extend([
+    Panel {
+      title: "Drop rate (%)",
+      series: vec![series("drop", "#d62728", &|i| {
+        metrics[i].drop_rate.map(|d| d * 100.0)
+      })],
+    },
+    Panel {
+      title: "Latency (ms)",
+      series: vec![
+        series("avg", "#2ca02c", &|i| metrics[i].avg_latency),
+        series("p50", "#9467bd", &|i| percentile(i, 0.5)),
+        series("p90", "#8c564b", &|i| percentile(i, 0.9)),
+        series("p99", "#e377c2", &|i| percentile(i, 0.99)),
+      ],
+    },
+  ]);
+  panels
+}
+
+/// Render the recording as an SVG document of the given width in pixels.
+pub fn plot(rec: &StatsRecording, title: &str, width: u32) -> String {
+  let step_secs = duration_from_time_value(rec.step_size).as_secs_f64();
+  let metrics: Vec<StepMetrics> = rec
+    .steps
+    .iter()
+    .map(|(t, s)| StepMetrics::new(*t, s, rec.step_size))
+    .collect();
+  let panels = panels(rec, &metrics);
+
+  let width = width as f64;
+  let height = MARGIN_TOP + panels.len() as f64 * (PANEL_HEIGHT + PANEL_GAP);
+  let plot_width = width - MARGIN_LEFT - MARGIN_RIGHT;
+  let x_min = metrics.first().unwrap().secs;
+  let x_max = (metrics.last().unwrap().secs + step_secs).max(x_min + step_secs);
+  let x_of = |secs: f64| MARGIN_LEFT + (secs - x_min) / (x_max - x_min) * plot_width;
+  let x_step = tick_step(x_max - x_min, (plot_width / 100.0).max(1.0));
+
+  // Writing to a String can't fail, so the results of write! are ignored.
+  let mut svg = String::new();
+  let _ = writeln!(
+    svg,
+    r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="sans-serif" font-size="12">"#
+  );
+  let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+  let _ = writeln!(
+    svg,
+    r#"<text x="{MARGIN_LEFT}" y="24" font-size="16" font-weight="bold">{}</text>"#,
+    escape(title)
+  );
+
+  for (panel_idx, panel) in panels.iter().enumerate() {
+    let top = MARGIN_TOP + panel_idx as f64 * (PANEL_HEIGHT + PANEL_GAP) + 20.0;
+    let bottom = top + PANEL_HEIGHT;
+    let y_max_value = panel
+      .series
+      .iter()
+      .flat_map(|s| s.values.iter().flatten())
+      .cloned()
+      .fold(0.0, f64::max);
+    let y_step = tick_step(if y_max_value > 0.0 { y_max_value } else { 1.0 }, 4.0);
+    let y_max = (y_max_value / y_step).ceil().max(1.0) * y_step;
+    let y_of = |v: f64| bottom - v / y_max * PANEL_HEIGHT;
+
+    let _ = writeln!(
+      svg,
+      r#"<text x="{MARGIN_LEFT}" y="{}" font-weight="bold">{}</text>"#,
+      top - 8.0,
+      panel.title
+    );
+    for (i, s) in panel.series.iter().enumerate() {
+      let x = width - MARGIN_RIGHT - (panel.series.len() - i) as f64 * 70.0;
+      let _ = writeln!(
+        svg,
+        r#"<rect x="{x}" y="{}" width="12" height="3" fill="{}"/><text x="{}" y="{}">{}</text>"#,
+        top - 14.0,
+        s.color,
+        x + 16.0,
+        top - 8.0,
+        s.name
+      );
+    }
+
+    // Grid and axes.
+    let mut v = 0.0;
+    while v <= y_max + y_step / 2.0 {
+      let y = y_of(v);
+      let _ = writeln!(
+        svg,
+        r##"<line x1="{MARGIN_LEFT}" y1="{y:.1}" x2="{}" y2="{y:.1}" stroke="#e0e0e0"/><text x="{}" y="{:.1}" text-anchor="end">{}</text>"##,
+        width - MARGIN_RIGHT,
+        MARGIN_LEFT - 6.0,
+        y + 4.0,
+        format_tick(v)
+      );
+      v += y_step;
+    }
+    let mut t = (x_min / x_step).ceil() * x_step;
+    while t <= x_max {
+      let x = x_of(t);
+      let _ = writeln!(
+        svg,
+        r##"<line x1="{x:.1}" y1="{top}" x2="{x:.1}" y2="{bottom}" stroke="#e0e0e0"/><text x="{x:.1}" y="{}" text-anchor="middle">{}s</text>"##,
+        bottom + 16.0,
+        format_tick(t)
+      );
+      t += x_step;
+    }
+    let _ = writeln!(
+      svg,
+      r#"<rect x="{MARGIN_LEFT}" y="{top}" width="{plot_width}" height="{PANEL_HEIGHT}" fill="none" stroke="black"/>"#
+    );
+
+    // One path per series, starting a new subpath after each gap.  Values are
+    // drawn at the middle of their step.
+    for s in panel.series.iter() {
+      let mut d = String::new();
+      let mut pen_down = false;
+      for (m, value) in metrics.iter().zip(s.values.iter()) {
+        match value {
+          Some(v) => {
+            let cmd = if pen_down { 'L' } else { 'M' };
+            let _ = write!(d, "{cmd}{:.1},{:.1} ", x_of(m.secs + step_secs / 2.0), y_of(*v));
+            pen_down = true;
+          }
+          None => pen_down = false,
+        }
+      }
+      if !d.is_empty() {
+        let _ = writeln!(
+          svg,
+          r#"<path d="{}" fill="none" stroke="{}" stroke-width="1.5" stroke-linejoin="round"/>"#,
+          d.trim_end(),
+          s.color
+        );
+      }
+    }
+  }
+  let _ = writeln!(svg, "</svg>");
+  svg
+}
@@ -0,0 +1,247 @@
+//! Reading stats files back, in any of the formats the sinks write.
+//!
+//! Only the raw counters are read, and derived columns like `drop_rate` are
+//! recalculated from them, so that steps can be merged exactly.  Per-socket
+//! rows are skipped in favour of the totals.  The file written with
+//! `--summary-file` is read as a single step lasting the whole run.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::errors::AppError;
+use crate::stats::{duration_from_time_value, get_time_value_from_duration, Stats, StatsFormat};
+
+/// The steps read from a stats file.
+pub struct StatsRecording {
+  /// Duration of each step, in time units, guessed from the spacing of the
+  /// steps.
+  pub step_size: u64,
+
+  /// Totals of each step by start time, in order.  Time values are relative
+  /// to the start of the run for CSV and JSON Lines, and to the first step
+  /// for the formats with wall-clock timestamps.
+  pub steps: Vec<(u64, Stats)>,
+
+  /// Whether this is a run summary, a single step covering the whole run.
+  pub is_summary: bool,
+}
+
+impl StatsRecording {
+  /// Read a stats file in the given format, or the one implied by its
+  /// extension.
+  pub fn read(path: &Path, format: Option<StatsFormat>) -> Result<Self, AppError> {
+    let path_str = path.display().to_string();
+    let content = fs::read_to_string(path).map_err(|e| AppError::IOError("read stats file", e))?;
+    let format = format.unwrap_or_else(|| StatsFormat::from_path(path));
+    let mut parser = Parser {
+      path: &path_str,
+      steps: BTreeMap::new(),
+      duration: None,
+      wallclock: matches!(format, StatsFormat::Influx | StatsFormat::Graphite),
+    };
+    match format {
+      StatsFormat::Csv => parser.parse_csv(&content)?,
+      StatsFormat::Jsonl => parser.parse_jsonl(&content)?,
+      StatsFormat::Influx => parser.parse_influx(&content)?,
+      StatsFormat::Graphite => parser.parse_graphite(&content)?,
+    }
+    if parser.steps.is_empty() {
+      return Err(AppError::EmptyStatsFile(path_str));
+    }
+
+    let first = *parser.steps.keys().next().unwrap();
+    let steps: Vec<(u64, Stats)> = parser
+      .steps
+      .into_iter()
+      .map(|(t, s)| {
+        let t = if parser.wallclock { t - first } else { t };
+        (get_time_value_from_duration(t), s)
+      })
+      .collect();
+    let step_size = parser.duration.unwrap_or_else(|| {
+      steps
+        .windows(2)
+        .map(|w| w[1].0 - w[0].0)
+        .filter(|&d| d > 0)
+        .min()
+        .unwrap_or(1)
+    });
+    Ok(Self {
+      step_size,
+      steps,
+      is_summary: parser.duration.is_some(),
+    })
+  }
+
+  /// Only keep steps starting within `from..to`, in time units.
+  pub fn trim(&mut self, from: Option<u64>, to: Option<u64>) {
+    self.steps.retain(|(t, _)| from.map_or(true, |f| *t >= f) && to.map_or(true, |e| *t < e));
+  }
+
+  /// Sum of all steps.
+  pub fn total(&self) -> Stats {
+    let total = Stats::default();
+    for (_, s) in self.steps.iter() {
+      total.add(s);
+    }
+    total
+  }
+}
+
+struct Parser<'a> {
+  path: &'a str,
+
+  /// Steps by time since the run (or the epoch, for `wallclock`).
+  steps: BTreeMap<Duration, Stats>,
+
+  /// Step duration given in the file, for run summaries.
+  duration: Option<u64>,
+  wallclock: bool,
+}
+
+impl Parser<'_> {
+  fn error(&self, line_no: usize, msg: impl Into<String>) -> AppError {
+    AppError::StatsParseError(self.path.to_owned(), line_no + 1, msg.into())
+  }
+
+  fn parse_number(&self, line_no: usize, s: &str) -> Result<f64, AppError> {
+    s.parse()
+      .map_err(|_| self.error(line_no, format!("invalid number {s:?}")))
+  }
+
+  fn set(&mut self, time: Duration, name: &str, value: f64) {
+    self.steps.entry(time).or_default().set_column(name, value);
+  }
+
+  fn parse_csv(&mut self, content: &str) -> Result<(), AppError> {
+    let mut lines = content.lines().enumerate();
+    let Some((_, header)) = lines.next() else {
+      return Ok(());
+    };
+    let names: Vec<&str> = header.split(',').collect();
+    if names.first() != Some(&"time") {
+      return Err(self.error(0, "expected a header starting with \"time\""));
+    }
+    let socket_col = names.iter().position(|&n| n == "socket");
+    for (line_no, line) in lines {
+      if line.is_empty() {
+        continue;
+      }
+      let values: Vec<&str> = line.split(',').collect();
+      if values.len() != names.len() {
+        return Err(self.error(line_no, "wrong number of columns"));
+      }
+      if socket_col.map_or(false, |i| values[i] != "all") {
+        continue;
+      }
+      let time = duration_from_time_value(self.parse_number(line_no, values[0])? as u64);
+      for (name, value) in names.iter().zip(values.iter()).skip(1) {
+        if value.is_empty() || Some(name) == socket_col.map(|i| &names[i]) {
+          continue;
+        }
+        let value = self.parse_number(line_no, value)?;
+        self.set(time, name, value);
+      }
+    }
+    Ok(())
+  }
+
+  fn parse_jsonl(&mut self, content: &str) -> Result<(), AppError> {
+    // We only need to read what JsonlStatsFile writes, which is a flat object
+    // of numbers, with no commas or colons in keys or strings.
+    for (line_no, line) in content.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      let inner = line
+        .strip_prefix('{')
+        .and_then(|l| l.strip_suffix('}'))
+        .ok_or_else(|| self.error(line_no, "expected an object"))?;
+      let mut fields = Vec::new();
+      for field in inner.split(',') {
+        let (key, value) = field
+          .split_once(':')
+          .ok_or_else(|| self.error(line_no, "expected key: value"))?;
+        fields.push((key.trim().trim_matches('"'), value.trim()));
+      }
+      if fields.iter().any(|&(k, v)| k == "socket" && v != "\"all\"") {
+        continue;
+      }
+      let (_, time) = fields
+        .iter()
+        .find(|&&(k, _)| k == "time")
+        .ok_or_else(|| self.error(line_no, "missing time"))?;
+      let time = duration_from_time_value(self.parse_number(line_no, time)? as u64);
+      for &(key, value) in fields.iter() {
+        // Non-finite values are written as null.
+        if key == "time" || key == "socket" || key == "local_port" || value == "null" {
+          continue;
+        }
+        let value = self.parse_number(line_no, value)?;
+        if key == "duration" {
+          self.duration = Some(value as u64);
+          continue;
+        }
+        self.set(time, key, value);
+      }
+    }
+    Ok(())
+  }
+
+  fn parse_influx(&mut self, content: &str) -> Result<(), AppError> {
+    for (line_no, line) in content.lines().enumerate() {
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      // Tags may contain escaped spaces, but field values never do.
+      let (rest, timestamp) = line
+        .rsplit_once(' ')
+        .ok_or_else(|| self.error(line_no, "missing timestamp"))?;
+      let (series, fields) = rest
+        .rsplit_once(' ')
+        .ok_or_else(|| self.error(line_no, "missing fields"))?;
+      if !series.starts_with("neuring,") && series != "neuring" {
+        continue;
+      }
+      if series.split(',').any(|t| t.starts_with("socket=") && t != "socket=all") {
+        continue;
+      }
+      let time = Duration::from_nanos(self.parse_number(line_no, timestamp)? as u64);
+      for field in fields.split(',') {
+        let (name, value) = field
+          .split_once('=')
+          .ok_or_else(|| self.error(line_no, "expected name=value"))?;
+        let value = self.parse_number(line_no, value.trim_end_matches('i'))?;
+        self.set(time, name, value);
+      }
+    }
+    Ok(())
+  }
+
+  fn parse_graphite(&mut self, content: &str) -> Result<(), AppError> {
+    for (line_no, line) in content.lines().enumerate() {
+      if line.is_empty() {
+        continue;
+      }
+      let mut parts = line.split(' ');
+      let (Some(path), Some(value), Some(timestamp)) = (parts.next(), parts.next(), parts.next())
+      else {
+        return Err(self.error(line_no, "expected \"path value timestamp\""));
+      };
+      let mut path_parts = path.split(';');
+      let Some(name) = path_parts.next().unwrap().strip_prefix("neuring.") else {
+        continue;
+      };
+      if path_parts.any(|t| t.starts_with("socket=") && t != "socket=all") {
+        continue;
+      }
+      let time = Duration::from_secs(self.parse_number(line_no, timestamp)? as u64);
+      let value = self.parse_number(line_no, value)?;
+      self.set(time, name, value);
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,83 @@
+//! Graceful shutdown on SIGINT / SIGTERM.
+//!
+//! On the first signal, senders stop sending, while receivers keep going for a
+//! drain period so that packets still in flight are counted.  After that, all
+//! threads return, and the remaining stats are flushed.  A second signal exits
+//! immediately.
+
+use std::{
+  io, mem,
+  sync::atomic::{AtomicBool, Ordering},
+  time::{Duration, Instant},
+};
+
+use crate::errors::AppError;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_sig: libc::c_int) {
+  if REQUESTED.swap(true, Ordering::Relaxed) {
+    unsafe { libc::_exit(130) };
+  }
+  // Only async-signal-safe functions can be used here, so no eprintln.
+  let msg = b"Shutting down, signal again to exit immediately.\n";
+  unsafe { libc::write(libc::STDERR_FILENO, msg.as_ptr() as *const _, msg.len()) };
+}
+
+/// Handle for checking whether shutdown was requested.
+#[derive(Debug, Clone, Copy)]
+pub struct Shutdown {
+  /// How long receivers keep going after shutdown was requested.
+  drain: Duration,
+}
+
+impl Shutdown {
+  /// Install the signal handlers.
+  pub fn install(drain: Duration) -> Result<Self, AppError> {
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+      unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig, &action, std::ptr::null_mut()) == -1 {
+          return Err(AppError::IOError("sigaction", io::Error::last_os_error()));
+        }
+      }
+    }
+    Ok(Self { drain })
+  }
+
+  /// Whether shutdown was requested, at which point senders should stop.
+  pub fn requested(&self) -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+  }
+
+  /// Create a timer for a receive loop to know when to stop.
+  pub fn drain_timer(&self) -> DrainTimer {
+    DrainTimer {
+      drain: self.drain,
+      deadline: None,
+    }
+  }
+}
+
+/// Tracks the drain period of one receive loop.
+#[derive(Debug)]
+pub struct DrainTimer {
+  drain: Duration,
+  deadline: Option<Instant>,
+}
+
+impl DrainTimer {
+  /// Whether the drain period after a shutdown request has passed.  This needs
+  /// to be called regularly, since the drain period starts the first time it
+  /// notices the request.
+  pub fn done(&mut self) -> bool {
+    if !REQUESTED.load(Ordering::Relaxed) {
+      return false;
+    }
+    let drain = self.drain;
+    let deadline = *self.deadline.get_or_insert_with(|| Instant::now() + drain);
+    Instant::now() >= deadline
+  }
+}
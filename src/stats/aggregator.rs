@@ -4,17 +4,47 @@
 //! It works by dividing the timeline into small, fixed-size steps, and keeping
 //! aggregated information for each step. There is also automatic eviction of
 //! old steps to keep memory usage bounded. Whenever a step access is attempted
-//! which goes over the range of steps we currently have, we evict all steps
-//! older than a certain threshold.
+//! which goes over the range of steps we currently have, or time is advanced,
+//! we evict all steps older than a certain threshold.
 //!
 //! It allows inserting new values into any steps that are still in memory, and
 //! supports exporting the aggregated information as a CSV file.
 //!
 //! The unit of the time values provided to this module can be arbitrary.
+//!
+//! The CPU time used by the process and the kernel's UDP error counters are
+//! also sampled by [`StatsAggregator::run_ticker`] whenever a new step starts,
+//! and attributed to the step before it.  This reads `/proc`, so it is kept off
+//! the packet path.
+//!
+//! Optionally, each socket can record into its own series within each step, so
+//! that a single misbehaving socket is visible.  Totals are then calculated at
+//! eviction time, so this does not add work on the packet path.
+//!
+//! [`StatsAggregator::run_ticker`] advances time in the background, so that
+//! steps are written out as soon as they pass the threshold, and idle periods
+//! are written out as empty steps instead of being skipped.
+//!
+//! Threads on the packet path should record through a [`StatsShard`], which
+//! has its own copy of the steps buffer.  Shards are only locked by their owner
+//! thread, except when steps are evicted, at which point the evicting thread
+//! merges the evicted steps of all shards into the shared buffer before writing
+//! them out.
 
-use std::{
-  sync::{atomic::AtomicU64, RwLock},
+use std::sync::{
+  atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+  Arc, Mutex, RwLock,
 };
+use std::thread;
+use std::time::Instant;
+
+use super::cpu_usage::{sample_cpu_times, CpuTimes};
+use super::one_way::OneWayDelays;
+use super::snmp::{sample_udp_errors, UdpErrorCounters};
+use super::{duration_from_time_value, get_time_value_now};
+
+/// Callback receiving each evicted step, see [`StatsAggregator::new`].
+type StatsWriter = dyn Fn(u64, Option<&SocketInfo>, &Stats) + Sync;
 
 pub struct StatsAggregator {
   /// Duration of each step.
@@ -26,10 +56,24 @@ pub struct StatsAggregator {
   /// Eviction threshold in time units.
   evict_threshold: u64,
 
+  /// Number of series in each step.  The first one is for stats not
+  /// attributed to any socket, followed by one for each socket if per-socket
+  /// stats are enabled.
+  nb_series: usize,
+
+  /// Local port of each socket, kept whether or not it has its own series.
+  socket_ports: Mutex<Vec<u16>>,
+
   /// The buffer
   locked_part: RwLock<LockedPart>,
 
-  stats_writer: Option<Box<dyn Fn(u64, &Stats) + Sync>>,
+  stats_writer: Option<Box<StatsWriter>>,
+
+  /// The last step for which we have sampled CPU usage and UDP errors.
+  sample_step: AtomicUsize,
+
+  /// CPU times and UDP error counters at the last sample.
+  last_sample: Mutex<(CpuTimes, UdpErrorCounters)>,
 }
 
 #[derive(Debug, Default)]
@@ -37,8 +81,59 @@ struct LockedPart {
   /// The index of the first step stored in the steps buffer.
   first_step_idx: usize,
 
-  /// The steps buffer.
-  steps_buf: Vec<Stats>,
+  /// The steps buffer, with `nb_series` stats for each step.
+  steps_buf: Vec<Box<[Stats]>>,
+
+  /// Buffers of all live shards.  These always cover the same steps as
+  /// `steps_buf`.
+  shards: Vec<Arc<Mutex<ShardBuf>>>,
+}
+
+#[derive(Debug)]
+struct ShardBuf {
+  /// The series this shard records into.
+  series: usize,
+
+  /// Same as [`LockedPart::first_step_idx`], copied here so that the owner
+  /// does not need to look at the shared buffer.
+  first_step_idx: usize,
+
+  steps: Vec<Stats>,
+}
+
+/// A per-thread handle for recording stats, obtained with
+/// [`StatsAggregator::socket_shard`].
+///
+/// Recording through a shard only locks a mutex private to the shard, which is
+/// uncontended except while steps are being evicted, and does not touch any
+/// cache lines shared with other threads.  When the shard is dropped, anything
+/// it has recorded is merged into the shared buffer.
+pub struct StatsShard<'a> {
+  agg: &'a StatsAggregator,
+  buf: Arc<Mutex<ShardBuf>>,
+}
+
+/// Identifies the socket a per-socket series belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketInfo {
+  pub socket_id: usize,
+  pub local_port: u16,
+}
+
+/// Upper bounds (inclusive) of the latency histogram buckets, in
+/// microseconds, so that round trips within a data center don't all end up in
+/// the first bucket.  There is one more bucket for everything above the last
+/// bound.
+pub const LATENCY_BUCKET_BOUNDS_US: [u64; 19] = [
+  10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000,
+  500_000, 1_000_000, 2_000_000, 5_000_000, 10_000_000,
+];
+
+pub const NB_LATENCY_BUCKETS: usize = LATENCY_BUCKET_BOUNDS_US.len() + 1;
+
+/// The last latency histogram bound, in milliseconds like the percentiles.
+pub fn max_latency_bound_ms() -> f64 {
+  *LATENCY_BUCKET_BOUNDS_US.last().unwrap() as f64 / 1e3
 }
 
 /// Aggregated statistics for a single step.
@@ -50,12 +145,273 @@ pub struct Stats {
   /// Number of packets received in this step.
   pub rx_packets: AtomicU64,
 
+  /// Payload bytes sent in this step.
+  pub tx_bytes: AtomicU64,
+
+  /// Payload bytes received in this step.
+  pub rx_bytes: AtomicU64,
+
+  /// Estimated bytes sent on the wire in this step, including protocol headers
+  /// and Ethernet framing.
+  pub tx_wire_bytes: AtomicU64,
+
+  /// Estimated bytes received on the wire in this step, including protocol
+  /// headers and Ethernet framing.
+  pub rx_wire_bytes: AtomicU64,
+
   /// Number of packets received that was sent in this step.  This is used to
   /// calculate the drop rate.
   pub rx_packets_sent_here: AtomicU64,
 
   /// Total latency of all packets that were *sent* in this step.
   pub total_latency_sent_here: AtomicU64,
+
+  /// The same in microseconds, from the nanosecond clocks in the packets,
+  /// since round trips within a data center are well below a time unit.
+  pub total_latency_us_sent_here: AtomicU64,
+
+  /// Histogram of the latency of packets that were *sent* in this step, with
+  /// buckets defined by [`LATENCY_BUCKET_BOUNDS_US`].
+  pub latency_hist: [AtomicU64; NB_LATENCY_BUCKETS],
+
+  /// Number of packets received in this step whose send step had already been
+  /// evicted, so they could not be counted against it.  Without these, such
+  /// packets would look like loss in the send step.
+  pub rx_late_packets: AtomicU64,
+
+  /// Total latency of the late packets received in this step.
+  pub total_latency_late: AtomicU64,
+
+  /// Highest latency of the late packets received in this step.
+  pub max_latency_late: AtomicU64,
+
+  /// Number of packets sent in this step that came back with timestamps from
+  /// an echo server in reflector mode.
+  pub reflected_packets: AtomicU64,
+
+  /// Total delay of those packets on the way to the server, in microseconds,
+  /// corrected by the estimated offset of its clock.
+  pub total_forward_delay_us: AtomicU64,
+
+  /// Total delay of those packets on the way back, in microseconds.
+  pub total_reverse_delay_us: AtomicU64,
+
+  /// Total time those packets spent in the server, in microseconds.
+  pub total_turnaround_us: AtomicU64,
+
+  /// User CPU time used by the process during this step, in microseconds.
+  pub cpu_user_us: AtomicU64,
+
+  /// System CPU time used by the process during this step, in microseconds.
+  pub cpu_sys_us: AtomicU64,
+
+  /// CPU time used by io_uring SQPOLL threads during this step, in
+  /// microseconds.  This is already included in `cpu_user_us` and
+  /// `cpu_sys_us`.
+  pub cpu_sqpoll_us: AtomicU64,
+
+  /// CPU time used by the send threads during this step, in microseconds.
+  pub cpu_send_us: AtomicU64,
+
+  /// CPU time used by the recv threads during this step, in microseconds.
+  pub cpu_recv_us: AtomicU64,
+
+  /// CPU time used by the echo threads of the syscall echo server during this
+  /// step, in microseconds.
+  pub cpu_echo_us: AtomicU64,
+
+  /// Number of packets dropped by the kernel because our receive sockets were
+  /// full, as reported by `SO_RXQ_OVFL`.
+  pub rx_sock_drops: AtomicU64,
+
+  /// Increase in the system-wide UDP `RcvbufErrors` counter during this step.
+  pub udp_rcvbuf_errors: AtomicU64,
+
+  /// Increase in the system-wide UDP `SndbufErrors` counter during this step.
+  pub udp_sndbuf_errors: AtomicU64,
+
+  /// Increase in the system-wide UDP `InErrors` counter during this step.
+  pub udp_in_errors: AtomicU64,
+
+  /// Number of packets we failed to send with `EAGAIN`, i.e. because the
+  /// socket send buffer was full.
+  pub tx_err_eagain: AtomicU64,
+
+  /// Number of packets we failed to send with `ENOBUFS`.
+  pub tx_err_enobufs: AtomicU64,
+
+  /// Number of packets we failed to send with `ECONNREFUSED`, which happens
+  /// when a previous packet resulted in an ICMP port unreachable.
+  pub tx_err_econnrefused: AtomicU64,
+
+  /// Number of packets we failed to send for any other reason.
+  pub tx_err_other: AtomicU64,
+
+  /// Number of ICMP port unreachable errors received.
+  pub icmp_port_unreachable: AtomicU64,
+
+  /// Number of ICMP fragmentation needed (or ICMPv6 packet too big) errors
+  /// received.
+  pub icmp_frag_needed: AtomicU64,
+
+  /// The most recent MTU reported by a fragmentation needed error in this
+  /// step, or 0 if there were none.
+  pub icmp_reported_mtu: AtomicU64,
+
+  /// Number of ICMP time exceeded errors received.
+  pub icmp_ttl_exceeded: AtomicU64,
+
+  /// Number of other errors read from the socket error queue.
+  pub icmp_other: AtomicU64,
+}
+
+impl Stats {
+  /// Record packets that were sent, with their total payload and estimated
+  /// on-wire size.
+  pub fn count_tx(&self, nb_packets: u64, bytes: u64, wire_bytes: u64) {
+    self.tx_packets.fetch_add(nb_packets, Ordering::Relaxed);
+    self.tx_bytes.fetch_add(bytes, Ordering::Relaxed);
+    self.tx_wire_bytes.fetch_add(wire_bytes, Ordering::Relaxed);
+  }
+
+  /// Record packets that were received, with their total payload and estimated
+  /// on-wire size.
+  pub fn count_rx(&self, nb_packets: u64, bytes: u64, wire_bytes: u64) {
+    self.rx_packets.fetch_add(nb_packets, Ordering::Relaxed);
+    self.rx_bytes.fetch_add(bytes, Ordering::Relaxed);
+    self.rx_wire_bytes.fetch_add(wire_bytes, Ordering::Relaxed);
+  }
+
+  /// Record a packet that was sent in this step and came back with the given
+  /// latency, in time units and in microseconds.
+  pub fn record_latency(&self, latency: u64, latency_us: u64) {
+    self.rx_packets_sent_here.fetch_add(1, Ordering::Relaxed);
+    self.total_latency_sent_here.fetch_add(latency, Ordering::Relaxed);
+    self.total_latency_us_sent_here.fetch_add(latency_us, Ordering::Relaxed);
+    let bucket = LATENCY_BUCKET_BOUNDS_US.partition_point(|&bound| bound < latency_us);
+    self.latency_hist[bucket].fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Record a packet received in this step, with the given latency, whose send
+  /// step was already evicted.
+  pub fn record_late(&self, latency: u64) {
+    self.rx_late_packets.fetch_add(1, Ordering::Relaxed);
+    self.total_latency_late.fetch_add(latency, Ordering::Relaxed);
+    self.max_latency_late.fetch_max(latency, Ordering::Relaxed);
+  }
+
+  /// Record the one-way delays of a packet that was sent in this step and came
+  /// back with reflector timestamps.
+  pub fn record_one_way(&self, delays: &OneWayDelays) {
+    self.reflected_packets.fetch_add(1, Ordering::Relaxed);
+    self.total_forward_delay_us.fetch_add(delays.forward_us, Ordering::Relaxed);
+    self.total_reverse_delay_us.fetch_add(delays.reverse_us, Ordering::Relaxed);
+    self.total_turnaround_us.fetch_add(delays.turnaround_us, Ordering::Relaxed);
+  }
+
+  /// Average latency of the packets sent in this step, in milliseconds, or
+  /// `None` if none came back.  Stats files from before the microsecond total
+  /// only have the total in time units.
+  pub fn avg_latency(&self) -> Option<f64> {
+    let rx_sent_here = self.rx_packets_sent_here.load(Ordering::Acquire);
+    if rx_sent_here == 0 {
+      return None;
+    }
+    let total_us = self.total_latency_us_sent_here.load(Ordering::Acquire);
+    let total_ms = if total_us > 0 {
+      total_us as f64 / 1e3
+    } else {
+      duration_from_time_value(self.total_latency_sent_here.load(Ordering::Acquire)).as_secs_f64()
+        * 1e3
+    };
+    Some(total_ms / rx_sent_here as f64)
+  }
+
+  /// Estimate the latency below which the given fraction `q` of packets sent in
+  /// this step fall, in milliseconds, as the upper bound of the histogram
+  /// bucket it's in.
+  ///
+  /// Returns `None` if no packets came back, and infinity if the percentile
+  /// falls above the last bucket bound.
+  pub fn latency_percentile(&self, q: f64) -> Option<f64> {
+    let counts: Vec<u64> = self.latency_hist.iter().map(|c| c.load(Ordering::Acquire)).collect();
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+      return None;
+    }
+    let target = ((total as f64 * q).ceil() as u64).clamp(1, total);
+    let mut cumulative = 0u64;
+    for (i, count) in counts.iter().enumerate() {
+      cumulative += count;
+      if cumulative >= target {
+        let bound = LATENCY_BUCKET_BOUNDS_US.get(i);
+        return Some(bound.map_or(f64::INFINITY, |&bound| bound as f64 / 1e3));
+      }
+    }
+    unreachable!()
+  }
+
+  /// Add all counters of `other` into this one.
+  pub fn add(&self, other: &Stats) {
+    let counters = [
+      (&self.tx_packets, &other.tx_packets),
+      (&self.rx_packets, &other.rx_packets),
+      (&self.tx_bytes, &other.tx_bytes),
+      (&self.rx_bytes, &other.rx_bytes),
+      (&self.tx_wire_bytes, &other.tx_wire_bytes),
+      (&self.rx_wire_bytes, &other.rx_wire_bytes),
+      (&self.rx_packets_sent_here, &other.rx_packets_sent_here),
+      (&self.total_latency_sent_here, &other.total_latency_sent_here),
+      (&self.total_latency_us_sent_here, &other.total_latency_us_sent_here),
+      (&self.rx_late_packets, &other.rx_late_packets),
+      (&self.total_latency_late, &other.total_latency_late),
+      (&self.reflected_packets, &other.reflected_packets),
+      (&self.total_forward_delay_us, &other.total_forward_delay_us),
+      (&self.total_reverse_delay_us, &other.total_reverse_delay_us),
+      (&self.total_turnaround_us, &other.total_turnaround_us),
+      (&self.cpu_user_us, &other.cpu_user_us),
+      (&self.cpu_sys_us, &other.cpu_sys_us),
+      (&self.cpu_sqpoll_us, &other.cpu_sqpoll_us),
+      (&self.cpu_send_us, &other.cpu_send_us),
+      (&self.cpu_recv_us, &other.cpu_recv_us),
+      (&self.cpu_echo_us, &other.cpu_echo_us),
+      (&self.rx_sock_drops, &other.rx_sock_drops),
+      (&self.udp_rcvbuf_errors, &other.udp_rcvbuf_errors),
+      (&self.udp_sndbuf_errors, &other.udp_sndbuf_errors),
+      (&self.udp_in_errors, &other.udp_in_errors),
+      (&self.tx_err_eagain, &other.tx_err_eagain),
+      (&self.tx_err_enobufs, &other.tx_err_enobufs),
+      (&self.tx_err_econnrefused, &other.tx_err_econnrefused),
+      (&self.tx_err_other, &other.tx_err_other),
+      (&self.icmp_port_unreachable, &other.icmp_port_unreachable),
+      (&self.icmp_frag_needed, &other.icmp_frag_needed),
+      (&self.icmp_ttl_exceeded, &other.icmp_ttl_exceeded),
+      (&self.icmp_other, &other.icmp_other),
+    ];
+    for (this, other) in counters {
+      this.fetch_add(other.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+    for (this, other) in self.latency_hist.iter().zip(other.latency_hist.iter()) {
+      this.fetch_add(other.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+    let max_late = other.max_latency_late.load(Ordering::Acquire);
+    self.max_latency_late.fetch_max(max_late, Ordering::Relaxed);
+    let mtu = other.icmp_reported_mtu.load(Ordering::Acquire);
+    if mtu != 0 {
+      self.icmp_reported_mtu.store(mtu, Ordering::Relaxed);
+    }
+  }
+
+  /// Record `nb_packets` packets that failed to send with the given errno.
+  pub fn count_send_errors(&self, errno: Option<i32>, nb_packets: u64) {
+    let counter = match errno {
+      Some(libc::EAGAIN) => &self.tx_err_eagain,
+      Some(libc::ENOBUFS) => &self.tx_err_enobufs,
+      Some(libc::ECONNREFUSED) => &self.tx_err_econnrefused,
+      _ => &self.tx_err_other,
+    };
+    counter.fetch_add(nb_packets, Ordering::Relaxed);
+  }
 }
 
 impl StatsAggregator {
@@ -66,9 +422,12 @@ impl StatsAggregator {
   /// * `step_size`: The duration of each step.
   /// * `keep_time`: The total duration of time to keep in memory.
   /// * `evict_threshold`: The time threshold for evicting old steps.
+  /// * `nb_socket_series`: If non-zero, keep separate stats for this many
+  ///   sockets, recorded with [`Self::access_socket_step`].
   /// * `stats_writer`: An optional callback that will be called whenever a step
   ///   is evicted.  This can be used to write the aggregated statistics to a
-  ///   file, for example.
+  ///   file, for example.  It is called with the totals first, and then with
+  ///   the stats of each socket if per-socket stats are enabled.
   ///
   /// Passing a `evict_threshold` of 0 will disable eviction, and `stats_writer`
   /// will be called immediately for each step.
@@ -76,26 +435,59 @@ impl StatsAggregator {
     step_size: u64,
     keep_time: u64,
     evict_threshold: u64,
-    stats_writer: Option<impl Fn(u64, &Stats) + Sync + 'static>,
+    nb_socket_series: usize,
+    stats_writer: Option<impl Fn(u64, Option<&SocketInfo>, &Stats) + Sync + 'static>,
   ) -> Self {
     let max_steps = (keep_time / step_size + 1) as usize;
     let s = Self {
       step_size,
       max_steps,
       evict_threshold,
+      nb_series: 1 + nb_socket_series,
+      socket_ports: Mutex::new(Vec::new()),
       locked_part: RwLock::new(LockedPart {
         first_step_idx: 0,
         steps_buf: Vec::with_capacity(max_steps),
+        shards: Vec::new(),
       }),
       stats_writer: stats_writer.map(|f| Box::new(f) as _),
+      sample_step: AtomicUsize::new(0),
+      last_sample: Mutex::new((sample_cpu_times(), sample_udp_errors())),
     };
-    s.locked_part.write().unwrap().steps_buf.resize_with(max_steps, Default::default);
+    s.locked_part.write().unwrap().steps_buf.resize_with(max_steps, || s.new_step());
     s
   }
 
+  fn new_step(&self) -> Box<[Stats]> {
+    (0..self.nb_series).map(|_| Stats::default()).collect()
+  }
+
+  /// The duration of each step, in time units.
+  pub fn step_size(&self) -> u64 {
+    self.step_size
+  }
+
+  /// Record the local port of a socket, to be reported along with its series.
+  pub fn set_socket_local_port(&self, socket_id: usize, local_port: u16) {
+    let mut ports = self.socket_ports.lock().unwrap();
+    if ports.len() <= socket_id {
+      ports.resize(socket_id + 1, 0);
+    }
+    ports[socket_id] = local_port;
+  }
+
+  /// The local ports recorded with [`Self::set_socket_local_port`].
+  pub fn socket_local_ports(&self) -> Vec<u16> {
+    let ports = self.socket_ports.lock().unwrap();
+    ports.iter().copied().filter(|&p| p != 0).collect()
+  }
+
   /// Use a callback to access the statistics for a given step, allowing
   /// modification of the statistics.  Will create new steps / evict old steps.
   ///
+  /// This goes through the shared buffer, so prefer using a [`StatsShard`] on
+  /// the packet path.
+  ///
   /// ## Returns
   ///
   /// Returns `true` if the step was accessed, or `false` if the step was
@@ -111,35 +503,213 @@ impl StatsAggregator {
     if step_buf_idx >= self.max_steps {
       drop(read_lock);
       let mut write_lock = self.locked_part.write().unwrap();
-
-      // Evict any steps that are too old.
-      let mut front_ptr = 0usize;
-      let locked_part = &mut *write_lock;
-      let first_step_idx = &mut locked_part.first_step_idx;
-      let buf = &mut locked_part.steps_buf;
-      while time.saturating_sub(self.evict_threshold) > *first_step_idx as u64 * self.step_size && front_ptr < self.max_steps {
-        let s = &buf[front_ptr];
-        if let Some(stats_writer) = &self.stats_writer {
-          stats_writer(*first_step_idx as u64 * self.step_size, &s);
-        }
-        *first_step_idx += 1;
-        front_ptr += 1;
+      self.evict(&mut write_lock, step, time);
+      if step < write_lock.first_step_idx {
+        return false;
       }
+      f(&write_lock.steps_buf[step - write_lock.first_step_idx][0]);
+      true
+    } else {
+      f(&read_lock.steps_buf[step_buf_idx][0]);
+      true
+    }
+  }
 
-      if front_ptr == self.max_steps {
-        // Jump ahead, to avoid steps still being outside buf even when we
-        // evicted everything.
-        *first_step_idx = step;
-      }
+  /// Create a shard recording into the series of the given socket if
+  /// per-socket stats are enabled, or into the totals otherwise.
+  pub fn socket_shard(&self, socket_id: usize) -> StatsShard<'_> {
+    let series = if self.nb_series > 1 { socket_id + 1 } else { 0 };
+    let mut locked_part = self.locked_part.write().unwrap();
+    let buf = Arc::new(Mutex::new(ShardBuf {
+      series,
+      first_step_idx: locked_part.first_step_idx,
+      steps: (0..self.max_steps).map(|_| Stats::default()).collect(),
+    }));
+    locked_part.shards.push(buf.clone());
+    StatsShard { agg: self, buf }
+  }
 
-      drop(buf.drain(..front_ptr));
-      buf.resize_with(self.max_steps, Default::default);
+  /// Move time forward to `time`, evicting all steps that are now older than
+  /// the threshold.
+  pub fn advance(&self, time: u64) {
+    let step: usize = (time / self.step_size).try_into().unwrap();
+    let read_lock = self.locked_part.read().unwrap();
+    if self.nb_expired(read_lock.first_step_idx, time) == 0 {
+      return;
+    }
+    drop(read_lock);
+    self.evict(&mut self.locked_part.write().unwrap(), step, time);
+  }
 
-      f(&buf[step - *first_step_idx]);
-      true
+  /// Call [`Self::advance`] with the current time once per step, until `stop`
+  /// is set.  Also samples the CPU usage and UDP error counters as each step
+  /// starts.
+  pub fn run_ticker(&self, start_time: Instant, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+      thread::sleep(duration_from_time_value(self.step_size));
+      let time = get_time_value_now(start_time);
+      self.maybe_sample((time / self.step_size).try_into().unwrap());
+      self.advance(time);
+    }
+  }
+
+  /// Write out all steps up to and including the one containing `time`,
+  /// regardless of the eviction threshold.  Used on shutdown, so that the last
+  /// few steps are not lost.
+  pub fn flush(&self, time: u64) {
+    let step: usize = (time / self.step_size).try_into().unwrap();
+    // Attribute the CPU usage so far to the last step.
+    self.maybe_sample(step + 1);
+    let mut locked_part = self.locked_part.write().unwrap();
+    if step < locked_part.first_step_idx {
+      return;
+    }
+    let nb_evict = (step + 1 - locked_part.first_step_idx).min(self.max_steps);
+    self.evict_steps(&mut locked_part, nb_evict, step + 1);
+  }
+
+  /// Number of steps from the start of the buffer that ended more than the
+  /// threshold before `time`, up to the whole buffer.
+  fn nb_expired(&self, first_step_idx: usize, time: u64) -> usize {
+    let expired_before = time.saturating_sub(self.evict_threshold) / self.step_size;
+    (expired_before as usize).saturating_sub(first_step_idx).min(self.max_steps)
+  }
+
+  /// Evict steps that are older than the threshold at `time`, merging in what
+  /// the shards have recorded for them.  `step` is the step containing
+  /// `time`, which the buffer should cover afterwards if possible.
+  fn evict(&self, locked_part: &mut LockedPart, step: usize, time: u64) {
+    let first_step_idx = locked_part.first_step_idx;
+    let nb_evict = self.nb_expired(first_step_idx, time);
+    if nb_evict == 0 {
+      // Someone else got here first.
+      return;
+    }
+    let new_first_step_idx = if nb_evict == self.max_steps {
+      // Jump ahead, to avoid steps still being outside buf even when we
+      // evicted everything.
+      step.max(first_step_idx + nb_evict)
     } else {
-      f(&read_lock.steps_buf[step_buf_idx]);
-      true
+      first_step_idx + nb_evict
+    };
+    self.evict_steps(locked_part, nb_evict, new_first_step_idx);
+  }
+
+  /// Write out the first `nb_evict` steps, and move the start of the buffer to
+  /// `new_first_step_idx`.
+  fn evict_steps(&self, locked_part: &mut LockedPart, nb_evict: usize, new_first_step_idx: usize) {
+    let first_step_idx = locked_part.first_step_idx;
+    for shard in locked_part.shards.iter() {
+      let mut shard = shard.lock().unwrap();
+      let series = shard.series;
+      for (i, s) in shard.steps.drain(..nb_evict).enumerate() {
+        locked_part.steps_buf[i][series].add(&s);
+      }
+      shard.steps.resize_with(self.max_steps, Default::default);
+      shard.first_step_idx = new_first_step_idx;
+    }
+
+    for (i, s) in locked_part.steps_buf.drain(..nb_evict).enumerate() {
+      self.write_step((first_step_idx + i) as u64 * self.step_size, &s);
+    }
+    locked_part.steps_buf.resize_with(self.max_steps, || self.new_step());
+    locked_part.first_step_idx = new_first_step_idx;
+  }
+
+  /// Pass an evicted step to the writer, as totals followed by each socket.
+  fn write_step(&self, time: u64, series: &[Stats]) {
+    let Some(stats_writer) = &self.stats_writer else {
+      return;
+    };
+    if series.len() == 1 {
+      stats_writer(time, None, &series[0]);
+      return;
+    }
+    let total = Stats::default();
+    for s in series.iter() {
+      total.add(s);
+    }
+    stats_writer(time, None, &total);
+    let ports = self.socket_ports.lock().unwrap().clone();
+    for (socket_id, s) in series[1..].iter().enumerate() {
+      let info = SocketInfo {
+        socket_id,
+        local_port: ports.get(socket_id).copied().unwrap_or(0),
+      };
+      stats_writer(time, Some(&info), s);
+    }
+  }
+
+  /// If `step` is newer than any step we have seen so far, sample the CPU
+  /// usage and UDP error counters, and attribute the increase since the last
+  /// sample to the previous step.
+  ///
+  /// If the ticker was held up for a while, all the increase in between will be
+  /// attributed to the step just before `step`.
+  fn maybe_sample(&self, step: usize) {
+    let last_step = self.sample_step.load(Ordering::Relaxed);
+    if step <= last_step {
+      return;
+    }
+    if self
+      .sample_step
+      .compare_exchange(last_step, step, Ordering::Relaxed, Ordering::Relaxed)
+      .is_err()
+    {
+      // Someone else is sampling for this step.
+      return;
+    }
+    let now = (sample_cpu_times(), sample_udp_errors());
+    let (cpu, udp) = {
+      let mut last_sample = self.last_sample.lock().unwrap();
+      let delta = (now.0 - last_sample.0, now.1 - last_sample.1);
+      *last_sample = now;
+      delta
+    };
+    // This will not recurse further since the previous step is not newer than
+    // sample_step.
+    self.access_step((step as u64 - 1) * self.step_size, |stats| {
+      stats.cpu_user_us.fetch_add(cpu.user_us, Ordering::Relaxed);
+      stats.cpu_sys_us.fetch_add(cpu.sys_us, Ordering::Relaxed);
+      stats.cpu_sqpoll_us.fetch_add(cpu.sqpoll_us, Ordering::Relaxed);
+      stats.cpu_send_us.fetch_add(cpu.send_us, Ordering::Relaxed);
+      stats.cpu_recv_us.fetch_add(cpu.recv_us, Ordering::Relaxed);
+      stats.cpu_echo_us.fetch_add(cpu.echo_us, Ordering::Relaxed);
+      stats.udp_rcvbuf_errors.fetch_add(udp.rcvbuf_errors, Ordering::Relaxed);
+      stats.udp_sndbuf_errors.fetch_add(udp.sndbuf_errors, Ordering::Relaxed);
+      stats.udp_in_errors.fetch_add(udp.in_errors, Ordering::Relaxed);
+    });
+  }
+}
+
+impl StatsShard<'_> {
+  /// Same as [`StatsAggregator::access_step`], but recording into this shard.
+  pub fn access_step(&mut self, time: u64, f: impl FnOnce(&Stats)) -> bool {
+    let agg = self.agg;
+    let step: usize = (time / agg.step_size).try_into().unwrap();
+    let mut buf = self.buf.lock().unwrap();
+    if step >= buf.first_step_idx + agg.max_steps {
+      // Evicting needs to lock all the shards, including this one.
+      drop(buf);
+      agg.evict(&mut agg.locked_part.write().unwrap(), step, time);
+      buf = self.buf.lock().unwrap();
+    }
+    if step < buf.first_step_idx {
+      return false;
+    }
+    f(&buf.steps[step - buf.first_step_idx]);
+    true
+  }
+}
+
+impl Drop for StatsShard<'_> {
+  fn drop(&mut self) {
+    let mut locked_part = self.agg.locked_part.write().unwrap();
+    locked_part.shards.retain(|s| !Arc::ptr_eq(s, &self.buf));
+    let shard = self.buf.lock().unwrap();
+    debug_assert_eq!(shard.first_step_idx, locked_part.first_step_idx);
+    for (i, s) in shard.steps.iter().enumerate() {
+      locked_part.steps_buf[i][shard.series].add(s);
     }
   }
 }
@@ -0,0 +1,233 @@
+//! The values we output for each step, shared by all stats sinks so that they
+//! stay consistent with each other.
+
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+use crate::stats::{duration_from_time_value, Stats, NB_LATENCY_BUCKETS};
+
+/// Names of the latency histogram columns, one for each bucket in
+/// [`LATENCY_BUCKET_BOUNDS_US`](crate::stats::LATENCY_BUCKET_BOUNDS_US).  The
+/// buckets of older files were in milliseconds, and are not read back.
+pub const LATENCY_BUCKET_COLUMNS: [&str; NB_LATENCY_BUCKETS] = [
+  "latency_le_10us",
+  "latency_le_20us",
+  "latency_le_50us",
+  "latency_le_100us",
+  "latency_le_200us",
+  "latency_le_500us",
+  "latency_le_1000us",
+  "latency_le_2000us",
+  "latency_le_5000us",
+  "latency_le_10000us",
+  "latency_le_20000us",
+  "latency_le_50000us",
+  "latency_le_100000us",
+  "latency_le_200000us",
+  "latency_le_500000us",
+  "latency_le_1000000us",
+  "latency_le_2000000us",
+  "latency_le_5000000us",
+  "latency_le_10000000us",
+  "latency_le_inf",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum StatValue {
+  Int(u64),
+  Float(f64),
+}
+
+impl fmt::Display for StatValue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StatValue::Int(v) => write!(f, "{}", v),
+      StatValue::Float(v) => write!(f, "{}", v),
+    }
+  }
+}
+
+impl Stats {
+  /// Get the named values to output for this step, including derived values
+  /// like drop rate, average latency and throughput.  The names and their
+  /// order are always the same.
+  ///
+  /// `step_size` is the duration of the step in time units, used to calculate
+  /// rates.
+  pub fn columns(&self, step_size: u64) -> Vec<(&'static str, StatValue)> {
+    use StatValue::{Float, Int};
+
+    let step_secs = duration_from_time_value(step_size).as_secs_f64();
+    let mbps = |bytes: u64| bytes as f64 * 8.0 / 1e6 / step_secs;
+    let tx_packets = self.tx_packets.load(Ordering::Acquire);
+    let rx_packets = self.rx_packets.load(Ordering::Acquire);
+    let tx_bytes = self.tx_bytes.load(Ordering::Acquire);
+    let rx_bytes = self.rx_bytes.load(Ordering::Acquire);
+    let tx_wire_bytes = self.tx_wire_bytes.load(Ordering::Acquire);
+    let rx_wire_bytes = self.rx_wire_bytes.load(Ordering::Acquire);
+    let rx_packets_sent_here = self.rx_packets_sent_here.load(Ordering::Acquire);
+    let tot_latency = self.total_latency_sent_here.load(Ordering::Acquire);
+    let rx_late_packets = self.rx_late_packets.load(Ordering::Acquire);
+    let tot_latency_late = self.total_latency_late.load(Ordering::Acquire);
+    let reflected_packets = self.reflected_packets.load(Ordering::Acquire);
+    let tot_forward = self.total_forward_delay_us.load(Ordering::Acquire);
+    let tot_reverse = self.total_reverse_delay_us.load(Ordering::Acquire);
+    let tot_turnaround = self.total_turnaround_us.load(Ordering::Acquire);
+    // Averages of microsecond totals, in milliseconds like the latency.
+    let avg_ms = |total_us: u64| {
+      Float(if reflected_packets == 0 {
+        0.0
+      } else {
+        total_us as f64 / 1e3 / reflected_packets as f64
+      })
+    };
+    let cpu_user = self.cpu_user_us.load(Ordering::Acquire) as f64 / 1e6;
+    let cpu_sys = self.cpu_sys_us.load(Ordering::Acquire) as f64 / 1e6;
+    let cpu_sqpoll = self.cpu_sqpoll_us.load(Ordering::Acquire) as f64 / 1e6;
+    let cpu_send = self.cpu_send_us.load(Ordering::Acquire) as f64 / 1e6;
+    let cpu_recv = self.cpu_recv_us.load(Ordering::Acquire) as f64 / 1e6;
+    let cpu_echo = self.cpu_echo_us.load(Ordering::Acquire) as f64 / 1e6;
+    let mut columns = vec![
+      ("tx_packets", Int(tx_packets)),
+      ("rx_packets", Int(rx_packets)),
+      // Packets whose echo arrives after their step was written out can't be
+      // taken back from it, so they count as dropped here, and as late in the
+      // step they arrive in.
+      (
+        "drop_rate",
+        // A sink may see packets of a step before it knows they were sent.
+        Float(if rx_packets_sent_here == 0 || tx_packets == 0 {
+          0.0
+        } else {
+          1.0 - (rx_packets_sent_here as f64 / tx_packets as f64)
+        }),
+      ),
+      ("avg_latency", Float(self.avg_latency().unwrap_or(0.0))),
+      ("late_packets", Int(rx_late_packets)),
+      // The drop rate with this step's late packets taken off.  Late packets
+      // were sent in earlier steps, so this is only an estimate for a single
+      // step, but it adds up to the loss in the run summary.
+      (
+        "net_drop_rate",
+        // Guarded like drop_rate, so that servers report 0 for both.
+        Float(if rx_packets_sent_here + rx_late_packets == 0 || tx_packets == 0 {
+          0.0
+        } else {
+          1.0 - ((rx_packets_sent_here + rx_late_packets) as f64 / tx_packets as f64)
+        }),
+      ),
+      (
+        "avg_late_latency",
+        Float(if rx_late_packets == 0 {
+          0.0
+        } else {
+          tot_latency_late as f64 / rx_late_packets as f64
+        }),
+      ),
+      ("max_late_latency", Int(self.max_latency_late.load(Ordering::Acquire))),
+      ("avg_forward_delay", avg_ms(tot_forward)),
+      ("avg_reverse_delay", avg_ms(tot_reverse)),
+      ("avg_turnaround", avg_ms(tot_turnaround)),
+      ("tx_bytes", Int(tx_bytes)),
+      ("rx_bytes", Int(rx_bytes)),
+      ("tx_wire_bytes", Int(tx_wire_bytes)),
+      ("rx_wire_bytes", Int(rx_wire_bytes)),
+      ("tx_mbps", Float(mbps(tx_bytes))),
+      ("rx_mbps", Float(mbps(rx_bytes))),
+      ("tx_wire_mbps", Float(mbps(tx_wire_bytes))),
+      ("rx_wire_mbps", Float(mbps(rx_wire_bytes))),
+      ("cpu_user", Float(cpu_user)),
+      ("cpu_sys", Float(cpu_sys)),
+      ("cpu_sqpoll", Float(cpu_sqpoll)),
+      ("cpu_send", Float(cpu_send)),
+      ("cpu_recv", Float(cpu_recv)),
+      ("cpu_echo", Float(cpu_echo)),
+      (
+        "packets_per_cpu_sec",
+        Float(if cpu_user + cpu_sys == 0.0 {
+          0.0
+        } else {
+          (tx_packets + rx_packets) as f64 / (cpu_user + cpu_sys)
+        }),
+      ),
+      ("rx_sock_drops", Int(self.rx_sock_drops.load(Ordering::Acquire))),
+      ("udp_rcvbuf_errors", Int(self.udp_rcvbuf_errors.load(Ordering::Acquire))),
+      ("udp_sndbuf_errors", Int(self.udp_sndbuf_errors.load(Ordering::Acquire))),
+      ("udp_in_errors", Int(self.udp_in_errors.load(Ordering::Acquire))),
+      ("tx_err_eagain", Int(self.tx_err_eagain.load(Ordering::Acquire))),
+      ("tx_err_enobufs", Int(self.tx_err_enobufs.load(Ordering::Acquire))),
+      ("tx_err_econnrefused", Int(self.tx_err_econnrefused.load(Ordering::Acquire))),
+      ("tx_err_other", Int(self.tx_err_other.load(Ordering::Acquire))),
+      ("icmp_port_unreachable", Int(self.icmp_port_unreachable.load(Ordering::Acquire))),
+      ("icmp_frag_needed", Int(self.icmp_frag_needed.load(Ordering::Acquire))),
+      ("icmp_reported_mtu", Int(self.icmp_reported_mtu.load(Ordering::Acquire))),
+      ("icmp_ttl_exceeded", Int(self.icmp_ttl_exceeded.load(Ordering::Acquire))),
+      ("icmp_other", Int(self.icmp_other.load(Ordering::Acquire))),
+      // Raw counters, so that steps can be merged exactly later.
+      ("rx_packets_sent_here", Int(rx_packets_sent_here)),
+      ("total_latency_sent_here", Int(tot_latency)),
+      (
+        "total_latency_us_sent_here",
+        Int(self.total_latency_us_sent_here.load(Ordering::Acquire)),
+      ),
+      ("total_latency_late", Int(tot_latency_late)),
+      ("reflected_packets", Int(reflected_packets)),
+      ("total_forward_delay_us", Int(tot_forward)),
+      ("total_reverse_delay_us", Int(tot_reverse)),
+      ("total_turnaround_us", Int(tot_turnaround)),
+    ];
+    for (name, count) in LATENCY_BUCKET_COLUMNS.iter().zip(self.latency_hist.iter()) {
+      columns.push((name, Int(count.load(Ordering::Acquire))));
+    }
+    columns
+  }
+
+  /// Set the counter behind a column written by [`Self::columns`], for reading
+  /// stats files back.  Returns `false` for derived columns, which can't be
+  /// set, and unknown columns.
+  pub fn set_column(&self, name: &str, value: f64) -> bool {
+    let (counter, scale) = match name {
+      "tx_packets" => (&self.tx_packets, 1.0),
+      "rx_packets" => (&self.rx_packets, 1.0),
+      "late_packets" => (&self.rx_late_packets, 1.0),
+      "max_late_latency" => (&self.max_latency_late, 1.0),
+      "tx_bytes" => (&self.tx_bytes, 1.0),
+      "rx_bytes" => (&self.rx_bytes, 1.0),
+      "tx_wire_bytes" => (&self.tx_wire_bytes, 1.0),
+      "rx_wire_bytes" => (&self.rx_wire_bytes, 1.0),
+      "cpu_user" => (&self.cpu_user_us, 1e6),
+      "cpu_sys" => (&self.cpu_sys_us, 1e6),
+      "cpu_sqpoll" => (&self.cpu_sqpoll_us, 1e6),
+      "cpu_send" => (&self.cpu_send_us, 1e6),
+      "cpu_recv" => (&self.cpu_recv_us, 1e6),
+      "cpu_echo" => (&self.cpu_echo_us, 1e6),
+      "rx_sock_drops" => (&self.rx_sock_drops, 1.0),
+      "udp_rcvbuf_errors" => (&self.udp_rcvbuf_errors, 1.0),
+      "udp_sndbuf_errors" => (&self.udp_sndbuf_errors, 1.0),
+      "udp_in_errors" => (&self.udp_in_errors, 1.0),
+      "tx_err_eagain" => (&self.tx_err_eagain, 1.0),
+      "tx_err_enobufs" => (&self.tx_err_enobufs, 1.0),
+      "tx_err_econnrefused" => (&self.tx_err_econnrefused, 1.0),
+      "tx_err_other" => (&self.tx_err_other, 1.0),
+      "icmp_port_unreachable" => (&self.icmp_port_unreachable, 1.0),
+      "icmp_frag_needed" => (&self.icmp_frag_needed, 1.0),
+      "icmp_reported_mtu" => (&self.icmp_reported_mtu, 1.0),
+      "icmp_ttl_exceeded" => (&self.icmp_ttl_exceeded, 1.0),
+      "icmp_other" => (&self.icmp_other, 1.0),
+      "rx_packets_sent_here" => (&self.rx_packets_sent_here, 1.0),
+      "total_latency_sent_here" => (&self.total_latency_sent_here, 1.0),
+      "total_latency_us_sent_here" => (&self.total_latency_us_sent_here, 1.0),
+      "total_latency_late" => (&self.total_latency_late, 1.0),
+      "reflected_packets" => (&self.reflected_packets, 1.0),
+      "total_forward_delay_us" => (&self.total_forward_delay_us, 1.0),
+      "total_reverse_delay_us" => (&self.total_reverse_delay_us, 1.0),
+      "total_turnaround_us" => (&self.total_turnaround_us, 1.0),
+      _ => match LATENCY_BUCKET_COLUMNS.iter().position(|&n| n == name) {
+        Some(i) => (&self.latency_hist[i], 1.0),
+        None => return false,
+      },
+    };
+    counter.store((value * scale).round() as u64, Ordering::Relaxed);
+    true
+  }
+}
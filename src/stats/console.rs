@@ -0,0 +1,212 @@
+//! A live view of the stats on stderr, either as one line per interval or as a
+//! block redrawn in place on a terminal.
+//!
+//! Like other sinks, this is fed with evicted steps, so what is shown lags
+//! behind by the eviction threshold.
+//!
+//! In tty mode, the block is redrawn by a separate thread once per interval,
+//! showing the newest complete interval, so that intervals evicted together
+//! (e.g. after the ticker was held up) don't flash by.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::errors::AppError;
+use crate::stats::{
+  duration_from_time_value, RunInfo, SocketInfo, Stats, StatsSink, max_latency_bound_ms,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConsoleMode {
+  /// Print one line per interval
+  Plain,
+  /// Redraw a block of lines in place, for interactive terminals
+  Tty,
+}
+
+pub struct ConsoleSink {
+  mode: ConsoleMode,
+  run_info: RunInfo,
+
+  /// Length of each displayed interval, in time units.
+  interval: u64,
+
+  /// Start of the interval currently being accumulated.
+  current_start: Option<u64>,
+  current: Stats,
+
+  /// In tty mode, the block to draw and the thread drawing it.
+  screen: Arc<Screen>,
+  redraw_thread: Option<JoinHandle<()>>,
+}
+
+/// The lines of the newest complete interval, shared with the redraw thread.
+#[derive(Default)]
+struct Screen {
+  lines: Mutex<Option<Vec<String>>>,
+  stop: AtomicBool,
+}
+
+impl ConsoleSink {
+  pub fn new(mode: ConsoleMode, interval: u64, run_info: &RunInfo) -> Self {
+    let screen = Arc::new(Screen::default());
+    let redraw_thread = (mode == ConsoleMode::Tty).then(|| {
+      let screen = screen.clone();
+      let period = duration_from_time_value(interval);
+      thread::spawn(move || run_redraw(&screen, period))
+    });
+    Self {
+      mode,
+      run_info: run_info.clone(),
+      interval,
+      current_start: None,
+      current: Stats::default(),
+      screen,
+      redraw_thread,
+    }
+  }
+
+  fn print_interval(&mut self, start: u64) -> io::Result<()> {
+    let s = &self.current;
+    let secs = duration_from_time_value(self.interval).as_secs_f64();
+    let t = duration_from_time_value(start + self.interval).as_secs_f64();
+    let tx_packets = s.tx_packets.load(Ordering::Relaxed);
+    let rx_packets = s.rx_packets.load(Ordering::Relaxed);
+    let tx_pps = tx_packets as f64 / secs;
+    let rx_pps = rx_packets as f64 / secs;
+    let tx_mbps = s.tx_bytes.load(Ordering::Relaxed) as f64 * 8.0 / 1e6 / secs;
+    let rx_mbps = s.rx_bytes.load(Ordering::Relaxed) as f64 * 8.0 / 1e6 / secs;
+    let rx_sent_here = s.rx_packets_sent_here.load(Ordering::Relaxed);
+    let drop_rate = if rx_sent_here == 0 || tx_packets == 0 {
+      0.0
+    } else {
+      1.0 - rx_sent_here as f64 / tx_packets as f64
+    };
+    let avg_latency = s.avg_latency().unwrap_or(0.0);
+    let percentile = |q| match s.latency_percentile(q) {
+      None => "-".to_owned(),
+      Some(v) if v.is_infinite() => format!(">{}", max_latency_bound_ms()),
+      Some(v) => format!("{}", v),
+    };
+    let (p50, p90, p99) = (percentile(0.5), percentile(0.9), percentile(0.99));
+    let send_errors = [
+      &s.tx_err_eagain,
+      &s.tx_err_enobufs,
+      &s.tx_err_econnrefused,
+      &s.tx_err_other,
+    ]
+    .iter()
+    .map(|c| c.load(Ordering::Relaxed))
+    .sum::<u64>();
+    let icmp_errors = [
+      &s.icmp_port_unreachable,
+      &s.icmp_frag_needed,
+      &s.icmp_ttl_exceeded,
+      &s.icmp_other,
+    ]
+    .iter()
+    .map(|c| c.load(Ordering::Relaxed))
+    .sum::<u64>();
+    let sock_drops = s.rx_sock_drops.load(Ordering::Relaxed);
+
+    match self.mode {
+      ConsoleMode::Plain => {
+        writeln!(
+          io::stderr().lock(),
+          "t={t:.1}s tx={tx_pps:.0}pps/{tx_mbps:.1}Mbps rx={rx_pps:.0}pps/{rx_mbps:.1}Mbps drop={:.2}% lat avg={avg_latency:.2} p50={p50} p90={p90} p99={p99} errors send={send_errors} icmp={icmp_errors} sockdrop={sock_drops}",
+          drop_rate * 100.0,
+        )?;
+      }
+      ConsoleMode::Tty => {
+        let lines = vec![
+          format!("neuring {}  t={t:.1}s", self.run_info.mode),
+          format!("  tx      {tx_pps:>12.0} pps  {tx_mbps:>10.1} Mbit/s"),
+          format!("  rx      {rx_pps:>12.0} pps  {rx_mbps:>10.1} Mbit/s"),
+          format!("  drop    {:>11.2}%", drop_rate * 100.0),
+          format!("  latency avg {avg_latency:.2}  p50 {p50}  p90 {p90}  p99 {p99} (ms)"),
+          format!("  errors  send {send_errors}  icmp {icmp_errors}  sock drops {sock_drops}"),
+        ];
+        *self.screen.lines.lock().unwrap() = Some(lines);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Draw the newest lines of `screen` once per `period`, replacing what was
+/// drawn before, until it is stopped.
+fn run_redraw(screen: &Screen, period: Duration) {
+  let mut lines_drawn = 0;
+  loop {
+    // Check before taking the lines, so that the last ones are always drawn.
+    let stop = screen.stop.load(Ordering::Acquire);
+    if let Some(lines) = screen.lines.lock().unwrap().take() {
+      if redraw(&lines, lines_drawn).is_err() {
+        return;
+      }
+      lines_drawn = lines.len();
+    }
+    if stop {
+      return;
+    }
+    thread::park_timeout(period);
+  }
+}
+
+/// Replace the `lines_drawn` lines drawn last time with `lines`.
+fn redraw(lines: &[String], lines_drawn: usize) -> io::Result<()> {
+  let mut stderr = io::stderr().lock();
+  if lines_drawn > 0 {
+    // Move up and clear everything we drew last time.
+    write!(stderr, "\x1b[{}A\x1b[J", lines_drawn)?;
+  }
+  for line in lines.iter() {
+    writeln!(stderr, "{}", line)?;
+  }
+  Ok(())
+}
+
+impl StatsSink for ConsoleSink {
+  fn write(
+    &mut self,
+    time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    // Only show totals, per-socket rows would not fit on the screen.
+    if socket.is_some() {
+      return Ok(());
+    }
+    let interval_start = time - time % self.interval;
+    if self.current_start != Some(interval_start) {
+      if let Some(start) = self.current_start {
+        self
+          .print_interval(start)
+          .map_err(|e| AppError::IOError("write to stderr", e))?;
+      }
+      self.current = Stats::default();
+      self.current_start = Some(interval_start);
+    }
+    self.current.add(stat);
+    Ok(())
+  }
+}
+
+impl Drop for ConsoleSink {
+  /// Show the last interval, which would otherwise never be complete.
+  fn drop(&mut self) {
+    if let Some(start) = self.current_start {
+      let _ = self.print_interval(start);
+    }
+    if let Some(redraw_thread) = self.redraw_thread.take() {
+      self.screen.stop.store(true, Ordering::Release);
+      redraw_thread.thread().unpark();
+      let _ = redraw_thread.join();
+    }
+  }
+}
@@ -0,0 +1,146 @@
+//! Sampling of the CPU time used by this process, so that throughput can be
+//! related to how much CPU it costs.
+//!
+//! Process-wide user and system time comes from `getrusage`. In addition, we
+//! look through `/proc/self/task` for io_uring SQPOLL threads (named
+//! `iou-sqp-<pid>`), which on recent kernels are part of our thread group and
+//! therefore already included in the process-wide figures, but are interesting
+//! on their own since they do the actual work for the io_uring backends.
+//!
+//! The packet threads of the syscall backends are named after their role, see
+//! [`name_current_thread`], so their CPU time is also summed up per role from
+//! the same place.  The io_uring backends do their work on the main thread,
+//! which is not counted separately.
+
+use std::{ffi::CString, fs, mem, ops::Sub};
+
+/// Cumulative CPU times, in microseconds.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuTimes {
+  pub user_us: u64,
+  pub sys_us: u64,
+
+  /// Time (user + system) used by SQPOLL threads. This is a subset of
+  /// `user_us + sys_us`.
+  pub sqpoll_us: u64,
+
+  /// Time (user + system) used by the send, recv and echo threads
+  /// respectively.  These are also subsets of `user_us + sys_us`.
+  pub send_us: u64,
+  pub recv_us: u64,
+  pub echo_us: u64,
+}
+
+impl Sub for CpuTimes {
+  type Output = CpuTimes;
+
+  fn sub(self, rhs: Self) -> Self::Output {
+    CpuTimes {
+      user_us: self.user_us.saturating_sub(rhs.user_us),
+      sys_us: self.sys_us.saturating_sub(rhs.sys_us),
+      sqpoll_us: self.sqpoll_us.saturating_sub(rhs.sqpoll_us),
+      send_us: self.send_us.saturating_sub(rhs.send_us),
+      recv_us: self.recv_us.saturating_sub(rhs.recv_us),
+      echo_us: self.echo_us.saturating_sub(rhs.echo_us),
+    }
+  }
+}
+
+fn timeval_to_us(tv: libc::timeval) -> u64 {
+  tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64
+}
+
+/// The kinds of threads we report CPU time for, by the prefix of their name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadRole {
+  Send,
+  Recv,
+  Echo,
+}
+
+impl ThreadRole {
+  fn prefix(self) -> &'static str {
+    match self {
+      ThreadRole::Send => "send-",
+      ThreadRole::Recv => "recv-",
+      ThreadRole::Echo => "echo-",
+    }
+  }
+}
+
+/// Name the calling thread after its role and number, so that its CPU time is
+/// attributed to that role.  This is best-effort, since it only affects stats.
+pub fn name_current_thread(role: ThreadRole, n: usize) {
+  // The kernel truncates names to 15 bytes, which is plenty for ours.
+  let name = CString::new(format!("{}{n}", role.prefix())).unwrap();
+  unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) };
+}
+
+/// Get the cumulative CPU times of this process.  This reads `/proc`, so
+/// should not be called from the packet path.
+pub fn sample_cpu_times() -> CpuTimes {
+  let mut usage: libc::rusage = unsafe { mem::zeroed() };
+  if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } == -1 {
+    return CpuTimes::default();
+  }
+  let mut times = CpuTimes {
+    user_us: timeval_to_us(usage.ru_utime),
+    sys_us: timeval_to_us(usage.ru_stime),
+    ..Default::default()
+  };
+  add_thread_times(&mut times);
+  times
+}
+
+/// Sum the CPU time of the SQPOLL threads and of our own threads of each role
+/// that we can find in `/proc/self/task`.  Leaves them at 0 if there are none,
+/// or if the kernel does not expose them as part of our process.
+///
+/// Threads that exited since the last sample take their CPU time with them, so
+/// their role may see less time than it actually used in the last step.
+fn add_thread_times(times: &mut CpuTimes) {
+  let Ok(tasks) = fs::read_dir("/proc/self/task") else {
+    return;
+  };
+  let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+  if ticks_per_sec <= 0 {
+    return;
+  }
+  let to_us = |ticks: u64| ticks * 1_000_000 / ticks_per_sec as u64;
+  for task in tasks.flatten() {
+    let Ok(stat) = fs::read_to_string(task.path().join("stat")) else {
+      continue;
+    };
+    let Some((comm, user, sys)) = parse_task_stat(&stat) else {
+      continue;
+    };
+    let total = if comm.starts_with("iou-sqp") {
+      &mut times.sqpoll_us
+    } else if comm.starts_with(ThreadRole::Send.prefix()) {
+      &mut times.send_us
+    } else if comm.starts_with(ThreadRole::Recv.prefix()) {
+      &mut times.recv_us
+    } else if comm.starts_with(ThreadRole::Echo.prefix()) {
+      &mut times.echo_us
+    } else {
+      continue;
+    };
+    *total += to_us(user + sys);
+  }
+}
+
+/// Parse the command name, utime and stime (in clock ticks) out of a
+/// `/proc/<pid>/task/<tid>/stat` line.
+fn parse_task_stat(stat: &str) -> Option<(&str, u64, u64)> {
+  // The command name is enclosed in parentheses and may itself contain spaces
+  // or parentheses, so look for the last closing one.
+  let comm_start = stat.find('(')? + 1;
+  let comm_end = stat.rfind(')')?;
+  let comm = &stat[comm_start..comm_end];
+  // Fields after the command name start from field 3 (state), and utime and
+  // stime are fields 14 and 15.
+  let mut fields = stat[comm_end + 1..].split_ascii_whitespace().skip(11);
+  let user = fields.next()?.parse().ok()?;
+  let sys = fields.next()?.parse().ok()?;
+  Some((comm, user, sys))
+}
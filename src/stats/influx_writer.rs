@@ -0,0 +1,157 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::errors::AppError;
+use crate::stats::{
+  duration_from_time_value, socket_label, BufferedStatsFile, RunInfo, SocketInfo, StatValue, Stats,
+  StatsSink,
+};
+
+/// Get the hostname of this machine, for tagging time series.
+fn get_hostname() -> String {
+  let mut buf = [0u8; 256];
+  let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut _, buf.len()) };
+  if res == -1 {
+    return "unknown".to_owned();
+  }
+  let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+  String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Get the absolute time of the step starting at `time`.
+fn step_wallclock(run_info: &RunInfo, time: u64) -> SystemTime {
+  run_info.start_wallclock + duration_from_time_value(time)
+}
+
+/// Escape a tag value for the InfluxDB line protocol.
+fn escape_influx_tag(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    if c == ',' || c == '=' || c == ' ' || c == '\\' {
+      out.push('\\');
+    }
+    out.push(c);
+  }
+  out
+}
+
+/// A buffered writer for stats in the InfluxDB line protocol, writing one point
+/// per step with nanosecond wall-clock timestamps.
+pub struct InfluxStatsFile {
+  f: BufferedStatsFile,
+  run_info: RunInfo,
+
+  /// Measurement name and tags, which are the same on every line except for
+  /// the socket tags.
+  line_prefix: String,
+}
+
+impl InfluxStatsFile {
+  pub fn new(path: impl AsRef<Path>, run_info: &RunInfo) -> Result<Self, AppError> {
+    let line_prefix = format!(
+      "neuring,mode={},host={},sockets={}",
+      escape_influx_tag(run_info.mode),
+      escape_influx_tag(&get_hostname()),
+      run_info.nb_sockets
+    );
+    Ok(Self {
+      f: BufferedStatsFile::create(path)?,
+      run_info: run_info.clone(),
+      line_prefix,
+    })
+  }
+}
+
+impl StatsSink for InfluxStatsFile {
+  fn write(
+    &mut self,
+    time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    let timestamp = step_wallclock(&self.run_info, time)
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let w = self.f.writer();
+    write!(w, "{}", self.line_prefix).map_err(|e| AppError::StatsFileError(e))?;
+    if self.run_info.per_socket {
+      write!(w, ",socket={}", socket_label(socket)).map_err(|e| AppError::StatsFileError(e))?;
+      if let Some(s) = socket {
+        write!(w, ",local_port={}", s.local_port).map_err(|e| AppError::StatsFileError(e))?;
+      }
+    }
+    write!(w, " ").map_err(|e| AppError::StatsFileError(e))?;
+    let mut sep = "";
+    for (name, value) in stat.columns(self.run_info.step_size) {
+      match value {
+        StatValue::Int(v) => write!(w, "{sep}{name}={v}i"),
+        StatValue::Float(v) if v.is_finite() => write!(w, "{sep}{name}={v}"),
+        // The line protocol has no infinities or NaN, so leave the field out.
+        StatValue::Float(_) => continue,
+      }
+      .map_err(|e| AppError::StatsFileError(e))?;
+      sep = ",";
+    }
+    writeln!(w, " {}", timestamp).map_err(|e| AppError::StatsFileError(e))?;
+    self.f.maybe_flush()
+  }
+}
+
+/// A buffered writer for stats in the Graphite plaintext protocol, using
+/// Graphite tags.  Note that Graphite only has second resolution, so with
+/// shorter stats intervals, only the last step in each second will be kept.
+pub struct GraphiteStatsFile {
+  f: BufferedStatsFile,
+  run_info: RunInfo,
+
+  /// Tags appended to every metric name.
+  tags: String,
+}
+
+impl GraphiteStatsFile {
+  pub fn new(path: impl AsRef<Path>, run_info: &RunInfo) -> Result<Self, AppError> {
+    // Graphite tag values can't contain ';', '~' or spaces.
+    let clean = |s: &str| s.replace([';', '~', ' '], "_");
+    let tags = format!(
+      ";mode={};host={};sockets={}",
+      clean(run_info.mode),
+      clean(&get_hostname()),
+      run_info.nb_sockets
+    );
+    Ok(Self {
+      f: BufferedStatsFile::create(path)?,
+      run_info: run_info.clone(),
+      tags,
+    })
+  }
+}
+
+impl StatsSink for GraphiteStatsFile {
+  fn write(
+    &mut self,
+    time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    let timestamp = step_wallclock(&self.run_info, time)
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    let socket_tags = match socket {
+      Some(s) => format!(";socket={};local_port={}", s.socket_id, s.local_port),
+      None if self.run_info.per_socket => ";socket=all".to_owned(),
+      None => String::new(),
+    };
+    let w = self.f.writer();
+    for (name, value) in stat.columns(self.run_info.step_size) {
+      if matches!(value, StatValue::Float(v) if !v.is_finite()) {
+        continue;
+      }
+      writeln!(w, "neuring.{}{}{} {} {}", name, self.tags, socket_tags, value, timestamp)
+        .map_err(|e| AppError::StatsFileError(e))?;
+    }
+    self.f.maybe_flush()
+  }
+}
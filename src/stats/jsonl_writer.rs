@@ -0,0 +1,51 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::errors::AppError;
+use crate::stats::{BufferedStatsFile, RunInfo, SocketInfo, StatValue, Stats, StatsSink};
+
+/// A buffered JSON Lines writer for stats, writing one object per step.
+pub struct JsonlStatsFile {
+  f: BufferedStatsFile,
+  step_size: u64,
+  per_socket: bool,
+}
+
+impl JsonlStatsFile {
+  pub fn new(path: impl AsRef<Path>, run_info: &RunInfo) -> Result<Self, AppError> {
+    Ok(Self {
+      f: BufferedStatsFile::create(path)?,
+      step_size: run_info.step_size,
+      per_socket: run_info.per_socket,
+    })
+  }
+}
+
+impl StatsSink for JsonlStatsFile {
+  fn write(
+    &mut self,
+    time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    // Column names are plain identifiers and values are numbers, so there is
+    // nothing to escape.  JSON has no infinities or NaN, so those are null.
+    let w = self.f.writer();
+    write!(w, "{{\"time\":{}", time).map_err(|e| AppError::StatsFileError(e))?;
+    match socket {
+      Some(s) => write!(w, ",\"socket\":{},\"local_port\":{}", s.socket_id, s.local_port),
+      None if self.per_socket => write!(w, ",\"socket\":\"all\""),
+      None => Ok(()),
+    }
+    .map_err(|e| AppError::StatsFileError(e))?;
+    for (name, value) in stat.columns(self.step_size) {
+      match value {
+        StatValue::Float(v) if !v.is_finite() => write!(w, ",\"{}\":null", name),
+        _ => write!(w, ",\"{}\":{}", name, value),
+      }
+      .map_err(|e| AppError::StatsFileError(e))?;
+    }
+    writeln!(w, "}}").map_err(|e| AppError::StatsFileError(e))?;
+    self.f.maybe_flush()
+  }
+}
@@ -3,9 +3,38 @@ use std::time::{Duration, Instant};
 
 pub use aggregator::*;
 
+mod columns;
+pub use columns::StatValue;
+
+mod sink;
+pub use sink::*;
+
 mod csv_writer;
 pub use csv_writer::*;
 
+mod jsonl_writer;
+pub use jsonl_writer::*;
+
+mod influx_writer;
+pub use influx_writer::*;
+
+mod console;
+pub use console::*;
+
+mod prometheus;
+pub use prometheus::*;
+
+mod summary;
+pub use summary::*;
+
+mod one_way;
+pub use one_way::*;
+
+mod cpu_usage;
+pub use cpu_usage::{name_current_thread, ThreadRole};
+
+mod snmp;
+
 pub fn get_time_value(start: Instant, current: Instant) -> u64 {
   get_time_value_from_duration(current.duration_since(start))
 }
@@ -17,3 +46,7 @@ pub fn get_time_value_now(start: Instant) -> u64 {
 pub fn get_time_value_from_duration(dur: Duration) -> u64 {
   dur.as_millis() as u64
 }
+
+pub fn duration_from_time_value(time: u64) -> Duration {
+  Duration::from_millis(time)
+}
@@ -0,0 +1,82 @@
+//! One-way delays from the timestamps an echo server in reflector mode stamps
+//! into the packets, in the spirit of TWAMP.
+//!
+//! The server's clock is not ours, so its timestamps are off by some unknown
+//! offset.  Like NTP, we estimate it by assuming that the way out and the way
+//! back take as long, in which case the offset is half the difference between
+//! the apparent forward and reverse delays.  Queueing makes the paths
+//! asymmetric, so only the packet with the lowest round trip time is trusted,
+//! and the estimate is renewed every few seconds to follow clock drift.
+
+use crate::pkt::PacketHeader;
+
+/// How long a lowest round trip time sample is used for, in nanoseconds.
+const WINDOW_NS: u64 = 2_000_000_000;
+
+/// The delays of one packet, split by where it spent them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OneWayDelays {
+  pub forward_us: u64,
+  pub reverse_us: u64,
+  pub turnaround_us: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OffsetSample {
+  /// Round trip time without the time spent in the server.
+  rtt: i64,
+  offset: i64,
+}
+
+/// Tracks the offset of a reflector's clock from ours, see the module
+/// documentation.
+#[derive(Debug, Default)]
+pub struct ClockOffsetEstimator {
+  /// The best sample of the current window, and of the one before, so that a
+  /// new window does not start from a single sample.
+  current: Option<OffsetSample>,
+  previous: Option<OffsetSample>,
+  window_start: u64,
+}
+
+impl ClockOffsetEstimator {
+  /// The offset of the reflector's clock from ours, in nanoseconds, if any
+  /// reflected packets were seen.
+  pub fn offset(&self) -> Option<i64> {
+    [self.current, self.previous]
+      .into_iter()
+      .flatten()
+      .min_by_key(|s| s.rtt)
+      .map(|s| s.offset)
+  }
+
+  /// Update the estimate with a packet that came back at `recv_clock` with
+  /// reflector timestamps, and get its one-way delays.
+  pub fn measure(&mut self, header: &PacketHeader, recv_clock: u64) -> OneWayDelays {
+    // The usual names: t1 and t4 are ours, t2 and t3 the server's.
+    let t1 = header.send_clock as i64;
+    let t2 = header.reflect_recv_clock as i64;
+    let t3 = header.reflect_send_clock as i64;
+    let t4 = recv_clock as i64;
+    let turnaround = (t3 - t2).max(0);
+    let rtt = (t4 - t1 - turnaround).max(0);
+
+    if recv_clock >= self.window_start + WINDOW_NS {
+      self.previous = self.current.take();
+      self.window_start = recv_clock;
+    }
+    if self.current.is_none_or(|s| rtt < s.rtt) {
+      self.current = Some(OffsetSample {
+        rtt,
+        offset: ((t2 - t1) + (t3 - t4)) / 2,
+      });
+    }
+
+    let forward = (t2 - t1 - self.offset().unwrap()).clamp(0, rtt);
+    OneWayDelays {
+      forward_us: forward as u64 / 1000,
+      reverse_us: (rtt - forward) as u64 / 1000,
+      turnaround_us: turnaround as u64 / 1000,
+    }
+  }
+}
@@ -0,0 +1,207 @@
+//! A Prometheus exporter, serving cumulative counters in the Prometheus text
+//! exposition format over a minimal built-in HTTP/1.1 server.
+//!
+//! The exporter is fed with evicted steps like any other sink, so it does not
+//! add any work on the packet path. The flip side is that metrics lag behind by
+//! the eviction threshold.
+//!
+//! Each connection is served on its own thread, so that a slow client can't
+//! hold up scrapes from others.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::AppError;
+use crate::stats::{SocketInfo, Stats, StatsSink, LATENCY_BUCKET_BOUNDS_US};
+
+/// The sink half of the exporter, which accumulates evicted steps.
+pub struct PrometheusExporter {
+  totals: Arc<Stats>,
+}
+
+impl PrometheusExporter {
+  /// Start serving metrics on `listen_addr` in a background thread.
+  pub fn start(listen_addr: &str) -> Result<Self, AppError> {
+    let listener =
+      TcpListener::bind(listen_addr).map_err(|e| AppError::IOError("metrics listen", e))?;
+    let totals = Arc::new(Stats::default());
+    let server_totals = totals.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+          continue;
+        };
+        let totals = server_totals.clone();
+        thread::spawn(move || {
+          if let Err(e) = handle_connection(stream, &totals) {
+            eprintln!("Warn: error serving metrics: {e}");
+          }
+        });
+      }
+    });
+    Ok(Self { totals })
+  }
+}
+
+impl StatsSink for PrometheusExporter {
+  fn write(
+    &mut self,
+    _time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    if socket.is_none() {
+      self.totals.add(stat);
+    }
+    Ok(())
+  }
+}
+
+fn handle_connection(mut stream: TcpStream, totals: &Stats) -> io::Result<()> {
+  stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+  // Read until the end of the request headers.  We don't expect a body.
+  let mut req = Vec::new();
+  let mut buf = [0u8; 1024];
+  while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+    let n = stream.read(&mut buf)?;
+    if n == 0 {
+      return Ok(());
+    }
+    req.extend_from_slice(&buf[..n]);
+    if req.len() > 8192 {
+      return write_response(&mut stream, "431 Request Header Fields Too Large", "");
+    }
+  }
+
+  let req = String::from_utf8_lossy(&req);
+  let mut request_line = req.lines().next().unwrap_or("").split(' ');
+  let method = request_line.next().unwrap_or("");
+  let path = request_line.next().unwrap_or("");
+  if method != "GET" {
+    return write_response(&mut stream, "405 Method Not Allowed", "");
+  }
+  if path != "/metrics" && !path.starts_with("/metrics?") {
+    return write_response(&mut stream, "404 Not Found", "");
+  }
+  write_response(&mut stream, "200 OK", &format_metrics(totals))
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+  write!(
+    stream,
+    "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+    body.len()
+  )
+}
+
+fn format_metrics(totals: &Stats) -> String {
+  let load = |c: &std::sync::atomic::AtomicU64| c.load(Ordering::Acquire);
+  let mut out = String::new();
+
+  let mut counter = |name: &str, help: &str, values: &[(&str, u64)]| {
+    writeln!(out, "# HELP neuring_{name} {help}").unwrap();
+    writeln!(out, "# TYPE neuring_{name} counter").unwrap();
+    for (labels, value) in values {
+      writeln!(out, "neuring_{name}{labels} {value}").unwrap();
+    }
+  };
+  counter("tx_packets_total", "Packets sent.", &[("", load(&totals.tx_packets))]);
+  counter("rx_packets_total", "Packets received.", &[("", load(&totals.rx_packets))]);
+  counter("tx_bytes_total", "Payload bytes sent.", &[("", load(&totals.tx_bytes))]);
+  counter("rx_bytes_total", "Payload bytes received.", &[("", load(&totals.rx_bytes))]);
+  counter(
+    "tx_wire_bytes_total",
+    "Estimated bytes sent on the wire, including headers and Ethernet framing.",
+    &[("", load(&totals.tx_wire_bytes))],
+  );
+  counter(
+    "rx_wire_bytes_total",
+    "Estimated bytes received on the wire, including headers and Ethernet framing.",
+    &[("", load(&totals.rx_wire_bytes))],
+  );
+  counter(
+    "rx_late_packets_total",
+    "Echoes received after the stats for their send time were already written.",
+    &[("", load(&totals.rx_late_packets))],
+  );
+  counter(
+    "rx_sock_drops_total",
+    "Packets dropped because our receive sockets were full.",
+    &[("", load(&totals.rx_sock_drops))],
+  );
+  counter(
+    "tx_errors_total",
+    "Packets that failed to send, by errno.",
+    &[
+      ("{errno=\"EAGAIN\"}", load(&totals.tx_err_eagain)),
+      ("{errno=\"ENOBUFS\"}", load(&totals.tx_err_enobufs)),
+      ("{errno=\"ECONNREFUSED\"}", load(&totals.tx_err_econnrefused)),
+      ("{errno=\"other\"}", load(&totals.tx_err_other)),
+    ],
+  );
+  counter(
+    "icmp_errors_total",
+    "ICMP errors received, by type.",
+    &[
+      ("{type=\"port_unreachable\"}", load(&totals.icmp_port_unreachable)),
+      ("{type=\"frag_needed\"}", load(&totals.icmp_frag_needed)),
+      ("{type=\"ttl_exceeded\"}", load(&totals.icmp_ttl_exceeded)),
+      ("{type=\"other\"}", load(&totals.icmp_other)),
+    ],
+  );
+  counter(
+    "udp_errors_total",
+    "Increase in the system-wide UDP error counters since start, by counter.",
+    &[
+      ("{counter=\"RcvbufErrors\"}", load(&totals.udp_rcvbuf_errors)),
+      ("{counter=\"SndbufErrors\"}", load(&totals.udp_sndbuf_errors)),
+      ("{counter=\"InErrors\"}", load(&totals.udp_in_errors)),
+    ],
+  );
+
+  writeln!(out, "# HELP neuring_cpu_seconds_total CPU time used by the process.").unwrap();
+  writeln!(out, "# TYPE neuring_cpu_seconds_total counter").unwrap();
+  for (mode, us) in [
+    ("user", load(&totals.cpu_user_us)),
+    ("system", load(&totals.cpu_sys_us)),
+    ("sqpoll", load(&totals.cpu_sqpoll_us)),
+  ] {
+    writeln!(out, "neuring_cpu_seconds_total{{mode=\"{mode}\"}} {}", us as f64 / 1e6).unwrap();
+  }
+  let help = "CPU time used by the packet threads, by role.";
+  writeln!(out, "# HELP neuring_thread_cpu_seconds_total {help}").unwrap();
+  writeln!(out, "# TYPE neuring_thread_cpu_seconds_total counter").unwrap();
+  for (role, us) in [
+    ("send", load(&totals.cpu_send_us)),
+    ("recv", load(&totals.cpu_recv_us)),
+    ("echo", load(&totals.cpu_echo_us)),
+  ] {
+    let secs = us as f64 / 1e6;
+    writeln!(out, "neuring_thread_cpu_seconds_total{{role=\"{role}\"}} {secs}").unwrap();
+  }
+
+  writeln!(out, "# HELP neuring_latency_ms Round-trip latency of echoed packets.").unwrap();
+  writeln!(out, "# TYPE neuring_latency_ms histogram").unwrap();
+  let mut cumulative = 0u64;
+  for (i, count) in totals.latency_hist.iter().enumerate() {
+    cumulative += load(count);
+    match LATENCY_BUCKET_BOUNDS_US.get(i) {
+      Some(&bound) => {
+        let bound = bound as f64 / 1e3;
+        writeln!(out, "neuring_latency_ms_bucket{{le=\"{bound}\"}} {cumulative}")
+      }
+      None => writeln!(out, "neuring_latency_ms_bucket{{le=\"+Inf\"}} {cumulative}"),
+    }
+    .unwrap();
+  }
+  let sum = load(&totals.total_latency_us_sent_here) as f64 / 1e3;
+  writeln!(out, "neuring_latency_ms_sum {sum}").unwrap();
+  writeln!(out, "neuring_latency_ms_count {}", load(&totals.rx_packets_sent_here)).unwrap();
+  out
+}
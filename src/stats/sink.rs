@@ -0,0 +1,291 @@
+//! Stats sinks, which receive each step as it is evicted from the
+//! [`StatsAggregator`](super::StatsAggregator).
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::ValueEnum;
+
+use crate::errors::AppError;
+use crate::stats::{
+  CsvStatsFile, GraphiteStatsFile, InfluxStatsFile, JsonlStatsFile, SocketInfo, Stats,
+};
+
+pub trait StatsSink: Send {
+  /// Output the stats for the step starting at `time`.  `socket` is `None`
+  /// for the totals of the step, and identifies the socket for per-socket
+  /// rows, which follow the totals.
+  fn write(&mut self, time: u64, socket: Option<&SocketInfo>, stat: &Stats)
+    -> Result<(), AppError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+  Csv,
+  Jsonl,
+  /// InfluxDB line protocol
+  Influx,
+  /// Graphite plaintext protocol, with tags
+  Graphite,
+}
+
+impl StatsFormat {
+  /// Guess the format from the extension of the stats file, defaulting to CSV.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension().and_then(|e| e.to_str()) {
+      Some("jsonl" | "ndjson" | "json") => StatsFormat::Jsonl,
+      Some("influx" | "lp") => StatsFormat::Influx,
+      Some("graphite") => StatsFormat::Graphite,
+      _ => StatsFormat::Csv,
+    }
+  }
+}
+
+/// Information about the run, for sinks which tag or timestamp their output.
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+  /// Name of the subcommand being run.
+  pub mode: &'static str,
+
+  pub nb_sockets: usize,
+
+  /// Whether per-socket rows are written after the totals of each step.
+  pub per_socket: bool,
+
+  /// Duration of each stats step, in time units.
+  pub step_size: u64,
+
+  /// Wall-clock time corresponding to time value 0.
+  pub start_wallclock: SystemTime,
+}
+
+/// A buffered output shared by all the sinks which write text.  The output can
+/// be a file, stdout (`-`), or a `udp://host:port` or `tcp://host:port`
+/// endpoint.
+///
+/// This implementation flushes the buffer every second so that the user can see
+/// the stats immediately.
+pub struct BufferedStatsFile {
+  f: BufWriter<Box<dyn Write + Send>>,
+  last_flush: Instant,
+}
+
+impl BufferedStatsFile {
+  pub fn create(path: impl AsRef<Path>) -> Result<Self, AppError> {
+    let path = path.as_ref();
+    let dest = path.to_str().unwrap_or("");
+    let f: Box<dyn Write + Send> = if dest == "-" {
+      Box::new(io::stdout())
+    } else if let Some(addr) = dest.strip_prefix("udp://") {
+      Box::new(UdpLineWriter::new(
+        connect_udp(addr).map_err(|e| AppError::StatsFileError(e))?,
+      ))
+    } else if let Some(addr) = dest.strip_prefix("tcp://") {
+      Box::new(TcpLineWriter::connect(addr).map_err(|e| AppError::StatsFileError(e))?)
+    } else {
+      Box::new(File::create(path).map_err(|e| AppError::StatsFileError(e))?)
+    };
+    Ok(Self {
+      f: BufWriter::new(f),
+      last_flush: Instant::now(),
+    })
+  }
+
+  pub fn writer(&mut self) -> &mut impl Write {
+    &mut self.f
+  }
+
+  /// Flush the buffer if it has not been flushed for a second.
+  pub fn maybe_flush(&mut self) -> Result<(), AppError> {
+    let now = Instant::now();
+    if now - self.last_flush > Duration::from_secs(1) {
+      self.f.flush().map_err(|e| AppError::StatsFileError(e))?;
+      self.last_flush = now;
+    }
+    Ok(())
+  }
+}
+
+/// Connect a UDP socket to `addr`, bound to the wildcard address of the same
+/// family.
+fn connect_udp(addr: &str) -> io::Result<UdpSocket> {
+  let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{addr} resolved to nothing"))
+  })?;
+  let bind_addr = match addr {
+    SocketAddr::V4(_) => "0.0.0.0:0",
+    SocketAddr::V6(_) => "[::]:0",
+  };
+  let sock = UdpSocket::bind(bind_addr)?;
+  sock.connect(addr)?;
+  Ok(sock)
+}
+
+/// Sends buffered text over UDP, making sure that lines are not split across
+/// datagrams, since receivers parse each datagram separately.
+struct UdpLineWriter {
+  sock: UdpSocket,
+  buf: Vec<u8>,
+}
+
+/// Keep datagrams small enough to not be fragmented on a typical network.
+const UDP_MAX_DATAGRAM: usize = 1400;
+
+impl UdpLineWriter {
+  fn new(sock: UdpSocket) -> Self {
+    Self {
+      sock,
+      buf: Vec::new(),
+    }
+  }
+
+  /// Send complete lines from the buffer.  If `all` is false, only send once
+  /// there is a full datagram worth of lines.
+  fn send_lines(&mut self, all: bool) -> io::Result<()> {
+    loop {
+      if !all && self.buf.len() < UDP_MAX_DATAGRAM {
+        return Ok(());
+      }
+      let limit = self.buf.len().min(UDP_MAX_DATAGRAM);
+      let end = match self.buf[..limit].iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        // A single line longer than the limit, send it on its own.
+        None => match self.buf.iter().position(|&b| b == b'\n') {
+          Some(i) => i + 1,
+          None => return Ok(()),
+        },
+      };
+      match self.sock.send(&self.buf[..end]) {
+        // Nobody listening (yet) is not an error for fire-and-forget UDP, we
+        // just lose these lines.
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+        res => {
+          res?;
+        }
+      }
+      self.buf.drain(..end);
+    }
+  }
+}
+
+impl Drop for UdpLineWriter {
+  fn drop(&mut self) {
+    let _ = self.send_lines(true);
+  }
+}
+
+impl Write for UdpLineWriter {
+  fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(data);
+    self.send_lines(false)?;
+    Ok(data.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.send_lines(true)
+  }
+}
+
+/// How long to wait before trying to reconnect a lost TCP stats connection.
+const TCP_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sends text over TCP, in whole lines.  Servers may run for days, and should
+/// not die when the collector restarts, so lines are dropped while the
+/// connection is down, and it is reconnected every few seconds.
+struct TcpLineWriter {
+  addr: String,
+  stream: Option<TcpStream>,
+  last_attempt: Instant,
+  buf: Vec<u8>,
+}
+
+impl TcpLineWriter {
+  fn connect(addr: &str) -> io::Result<Self> {
+    Ok(Self {
+      addr: addr.to_owned(),
+      stream: Some(TcpStream::connect(addr)?),
+      last_attempt: Instant::now(),
+      buf: Vec::new(),
+    })
+  }
+
+  /// Send the complete lines from the buffer, or drop them if the connection
+  /// is down.
+  fn send_lines(&mut self) {
+    let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') else {
+      return;
+    };
+    if self.stream.is_none() && self.last_attempt.elapsed() >= TCP_RECONNECT_INTERVAL {
+      self.last_attempt = Instant::now();
+      if let Ok(stream) = TcpStream::connect(&self.addr) {
+        eprintln!("Stats: reconnected to tcp://{}.", self.addr);
+        self.stream = Some(stream);
+      }
+    }
+    if let Some(stream) = &mut self.stream {
+      if let Err(e) = stream.write_all(&self.buf[..=end]) {
+        eprintln!(
+          "Warn: lost the stats connection to tcp://{}: {e}.  Dropping stats until it is back.",
+          self.addr
+        );
+        self.stream = None;
+        self.last_attempt = Instant::now();
+      }
+    }
+    self.buf.drain(..=end);
+  }
+}
+
+impl Write for TcpLineWriter {
+  fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(data);
+    self.send_lines();
+    Ok(data.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.send_lines();
+    Ok(())
+  }
+}
+
+/// Format the socket column of a row, `all` for the totals.
+pub fn socket_label(socket: Option<&SocketInfo>) -> String {
+  match socket {
+    Some(s) => s.socket_id.to_string(),
+    None => "all".to_owned(),
+  }
+}
+
+/// Open a stats file in the given format, or the one implied by its extension.
+pub fn open_stats_file(
+  path: impl AsRef<Path>,
+  format: Option<StatsFormat>,
+  run_info: &RunInfo,
+) -> Result<Box<dyn StatsSink>, AppError> {
+  let path = path.as_ref();
+  Ok(match format.unwrap_or_else(|| StatsFormat::from_path(path)) {
+    StatsFormat::Csv => Box::new(CsvStatsFile::new(path, run_info)?),
+    StatsFormat::Jsonl => Box::new(JsonlStatsFile::new(path, run_info)?),
+    StatsFormat::Influx => Box::new(InfluxStatsFile::new(path, run_info)?),
+    StatsFormat::Graphite => Box::new(GraphiteStatsFile::new(path, run_info)?),
+  })
+}
+
+/// Combine sinks into a callback suitable for
+/// [`StatsAggregator::new`](super::StatsAggregator::new), which writes each
+/// step to all of them in order.
+pub fn make_stats_writer(
+  sinks: Vec<Box<dyn StatsSink>>,
+) -> impl Fn(u64, Option<&SocketInfo>, &Stats) + Send + Sync + 'static {
+  let sinks = Mutex::new(sinks);
+  move |time, socket: Option<&SocketInfo>, stat: &Stats| {
+    for sink in sinks.lock().unwrap().iter_mut() {
+      sink.write(time, socket, stat).expect("failed to write stats");
+    }
+  }
+}
@@ -0,0 +1,60 @@
+//! Sampling of the kernel's UDP error counters from `/proc/net/snmp`.
+//!
+//! These counters are shared by the whole network namespace, so other
+//! processes using UDP will also contribute to them.
+
+use std::{fs, ops::Sub};
+
+/// Cumulative UDP error counters.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UdpErrorCounters {
+  /// Packets dropped because the receive buffer of a socket was full.
+  pub rcvbuf_errors: u64,
+
+  /// Packets dropped because the send buffer of a socket was full.
+  pub sndbuf_errors: u64,
+
+  /// All packets that could not be delivered for reasons other than no socket
+  /// listening on the port.  Includes `rcvbuf_errors`.
+  pub in_errors: u64,
+}
+
+impl Sub for UdpErrorCounters {
+  type Output = UdpErrorCounters;
+
+  fn sub(self, rhs: Self) -> Self::Output {
+    UdpErrorCounters {
+      rcvbuf_errors: self.rcvbuf_errors.saturating_sub(rhs.rcvbuf_errors),
+      sndbuf_errors: self.sndbuf_errors.saturating_sub(rhs.sndbuf_errors),
+      in_errors: self.in_errors.saturating_sub(rhs.in_errors),
+    }
+  }
+}
+
+/// Read the current UDP error counters. Returns all zeros if they could not be
+/// read.
+pub fn sample_udp_errors() -> UdpErrorCounters {
+  fs::read_to_string("/proc/net/snmp")
+    .ok()
+    .and_then(|s| parse_snmp(&s))
+    .unwrap_or_default()
+}
+
+/// The file consists of pairs of lines, the first one listing the field names
+/// and the second one listing the values, both prefixed with the protocol name.
+fn parse_snmp(snmp: &str) -> Option<UdpErrorCounters> {
+  let mut udp_lines = snmp.lines().filter(|l| l.starts_with("Udp: "));
+  let names = udp_lines.next()?.split_ascii_whitespace().skip(1);
+  let values = udp_lines.next()?.split_ascii_whitespace().skip(1);
+  let mut counters = UdpErrorCounters::default();
+  for (name, value) in names.zip(values) {
+    let field = match name {
+      "RcvbufErrors" => &mut counters.rcvbuf_errors,
+      "SndbufErrors" => &mut counters.sndbuf_errors,
+      "InErrors" => &mut counters.in_errors,
+      _ => continue,
+    };
+    *field = value.parse().ok()?;
+  }
+  Some(counters)
+}
@@ -0,0 +1,169 @@
+//! A summary of the whole run, printed on exit.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::control::PeerCounts;
+use crate::errors::AppError;
+use crate::stats::{
+  get_time_value_from_duration, SocketInfo, Stats, StatsSink, max_latency_bound_ms,
+};
+
+/// Accumulates the totals of all steps as they are written out.  Clones share
+/// the same totals, so one clone can be given to the aggregator as a sink, and
+/// another kept to print the summary.
+#[derive(Clone, Default)]
+pub struct RunSummary {
+  totals: Arc<Stats>,
+}
+
+impl StatsSink for RunSummary {
+  fn write(
+    &mut self,
+    _time: u64,
+    socket: Option<&SocketInfo>,
+    stat: &Stats,
+  ) -> Result<(), AppError> {
+    if socket.is_none() {
+      self.totals.add(stat);
+    }
+    Ok(())
+  }
+}
+
+impl RunSummary {
+  /// Write the totals as a single JSON object, with the same columns as the
+  /// JSON Lines stats file, computed over the whole run, plus its `duration`.
+  /// With the echo server's counts for this client, they are added too, along
+  /// with the loss on the way out and, for echo tests, on the way back.
+  pub fn write_file(
+    &self,
+    path: &Path,
+    elapsed: Duration,
+    server: Option<&PeerCounts>,
+  ) -> Result<(), AppError> {
+    let duration = get_time_value_from_duration(elapsed).max(1);
+    let mut out = format!("{{\"time\":0,\"duration\":{duration}");
+    for (name, value) in self.totals.columns(duration) {
+      out += &format!(",\"{}\":{}", name, value);
+    }
+    if let Some(server) = server {
+      out += &format!(
+        ",\"server_rx_packets\":{},\"server_tx_packets\":{},\"server_tx_errors\":{}",
+        server.rx_packets, server.tx_packets, server.tx_errors
+      );
+      let (forward, reverse) = self.split_loss(server);
+      out += &format!(",\"forward_lost\":{forward}");
+      if let Some(reverse) = reverse {
+        out += &format!(",\"reverse_lost\":{reverse}");
+      }
+    }
+    out += "}\n";
+    fs::write(path, out).map_err(|e| AppError::StatsFileError(e))
+  }
+
+  /// Split the loss into packets lost on the way to the server and on the way
+  /// back, given the server's counts for this client.  In one-way tests,
+  /// nothing comes back, so there is no way back.
+  fn split_loss(&self, server: &PeerCounts) -> (u64, Option<u64>) {
+    let load = |c: &std::sync::atomic::AtomicU64| c.load(Ordering::Acquire);
+    let forward = load(&self.totals.tx_packets).saturating_sub(server.rx_packets);
+    let rx_sent_here = load(&self.totals.rx_packets_sent_here);
+    if rx_sent_here == 0 {
+      return (forward, None);
+    }
+    let echoed = rx_sent_here + load(&self.totals.rx_late_packets);
+    (forward, Some(server.tx_packets.saturating_sub(echoed)))
+  }
+
+  /// Print the summary to stderr.  This should be called after all steps have
+  /// been flushed.  With the echo server's counts for this client, loss is
+  /// split between the way out and the way back.
+  pub fn print(&self, elapsed: Duration, server: Option<&PeerCounts>) {
+    let s = &self.totals;
+    let load = |c: &std::sync::atomic::AtomicU64| c.load(Ordering::Acquire);
+    let secs = elapsed.as_secs_f64();
+    let tx_packets = load(&s.tx_packets);
+    let rx_packets = load(&s.rx_packets);
+    let rx_sent_here = load(&s.rx_packets_sent_here);
+    let late_packets = load(&s.rx_late_packets);
+    let send_errors = load(&s.tx_err_eagain)
+      + load(&s.tx_err_enobufs)
+      + load(&s.tx_err_econnrefused)
+      + load(&s.tx_err_other);
+
+    eprintln!("Run summary ({secs:.1}s):");
+    eprintln!(
+      "  tx {tx_packets} packets ({:.0} pps, {:.1} Mbit/s)",
+      tx_packets as f64 / secs,
+      load(&s.tx_bytes) as f64 * 8.0 / 1e6 / secs
+    );
+    eprintln!(
+      "  rx {rx_packets} packets ({:.0} pps, {:.1} Mbit/s)",
+      rx_packets as f64 / secs,
+      load(&s.rx_bytes) as f64 * 8.0 / 1e6 / secs
+    );
+    if let (0, Some(server)) = (rx_sent_here, server) {
+      // One-way tests, where the sink only tells us what arrived.
+      let (lost, _) = self.split_loss(server);
+      eprintln!(
+        "  server received {} packets, lost {lost} ({:.3}%)",
+        server.rx_packets,
+        lost as f64 * 100.0 / tx_packets.max(1) as f64
+      );
+    }
+    if rx_sent_here > 0 {
+      // Only meaningful when we are the one sending and receiving the echoes.
+      let lost = tx_packets.saturating_sub(rx_sent_here + late_packets);
+      eprintln!(
+        "  lost {lost} ({:.3}%), late {late_packets}",
+        lost as f64 * 100.0 / tx_packets as f64
+      );
+      if let Some(server) = server {
+        let (forward, reverse) = self.split_loss(server);
+        let reverse = reverse.unwrap_or(0);
+        eprintln!(
+          "    forward {forward} ({:.3}%), reverse {reverse} ({:.3}%), server send errors {}",
+          forward as f64 * 100.0 / tx_packets as f64,
+          reverse as f64 * 100.0 / server.tx_packets.max(1) as f64,
+          server.tx_errors
+        );
+      }
+      let percentile = |q| match s.latency_percentile(q) {
+        None => "-".to_owned(),
+        Some(v) if v.is_infinite() => format!(">{}", max_latency_bound_ms()),
+        Some(v) => format!("{}", v),
+      };
+      eprintln!(
+        "  latency avg {:.3}  p50 {}  p90 {}  p99 {} (ms)",
+        s.avg_latency().unwrap(),
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99)
+      );
+      let reflected = load(&s.reflected_packets);
+      if reflected > 0 {
+        let avg = |c| load(c) as f64 / 1e3 / reflected as f64;
+        eprintln!(
+          "  one-way avg forward {:.3}  reverse {:.3}  server turnaround {:.3} (ms)",
+          avg(&s.total_forward_delay_us),
+          avg(&s.total_reverse_delay_us),
+          avg(&s.total_turnaround_us)
+        );
+      }
+    }
+    eprintln!(
+      "  errors send {send_errors}  sock drops {}  udp rcvbuf {}",
+      load(&s.rx_sock_drops),
+      load(&s.udp_rcvbuf_errors)
+    );
+    eprintln!(
+      "  cpu user {:.2}s  sys {:.2}s",
+      load(&s.cpu_user_us) as f64 / 1e6,
+      load(&s.cpu_sys_us) as f64 / 1e6
+    );
+  }
+}
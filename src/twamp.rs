@@ -0,0 +1,109 @@
+//! TWAMP-Light test packets (RFC 5357, unauthenticated mode), so that we can
+//! send to and reflect for other implementations, like network gear with a
+//! hardware responder.
+//!
+//! Unlike our own format, these packets carry no padding derived from the seed
+//! to check, and their timestamps are NTP timestamps from the wall clock.  All
+//! fields are big endian.
+
+use std::time::Duration;
+
+use crate::pkt::PacketHeader;
+use crate::stats::get_time_value_from_duration;
+
+/// Size of the fields of a test packet from the sender.
+pub const SENDER_HEAD_SIZE: usize = 14;
+
+/// Size of the fields of a reflected test packet.  Senders should pad their
+/// packets to at least this size, so that the reflected packets are as large.
+pub const REFLECTOR_HEAD_SIZE: usize = 41;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+/// The error estimate we send: not synchronized to UTC, and accurate to about
+/// a millisecond, as a multiplier of 1 and a scale of 22, i.e. 2^-10 seconds.
+const ERROR_ESTIMATE: u16 = (22 << 8) | 1;
+
+/// The TTL the sender should use, so that reflectors can tell whether the
+/// packets were routed.
+pub const SENDER_TTL: u8 = 255;
+
+/// Convert a time from [`clock_now`](crate::pkt::clock_now) to an NTP
+/// timestamp.
+fn ntp_from_clock(clock: u64) -> u64 {
+  let secs = clock / 1_000_000_000 + NTP_UNIX_OFFSET_SECS;
+  let frac = ((clock % 1_000_000_000) << 32) / 1_000_000_000;
+  (secs << 32) | frac
+}
+
+/// Convert an NTP timestamp back, rounding so that our own timestamps come
+/// back exactly.
+fn clock_from_ntp(ntp: u64) -> u64 {
+  let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET_SECS);
+  let nanos = ((ntp & 0xffff_ffff) * 1_000_000_000 + (1 << 31)) >> 32;
+  secs * 1_000_000_000 + nanos
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+  u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+  u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Write the fields of a test packet at the start of `buf`, which must be at
+/// least [`SENDER_HEAD_SIZE`] bytes long.  The rest of the buffer is the
+/// padding and is left as is.
+pub fn write_test_packet(seq: u32, send_clock: u64, buf: &mut [u8]) {
+  buf[0..4].copy_from_slice(&seq.to_be_bytes());
+  buf[4..12].copy_from_slice(&ntp_from_clock(send_clock).to_be_bytes());
+  buf[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+}
+
+/// Turn the test packet of `len` bytes at the start of `buf` into a reflected
+/// one in place, and return its length.  Packets shorter than
+/// [`REFLECTOR_HEAD_SIZE`] grow to that size, if `buf` has room; otherwise, and
+/// for packets too short to be test packets, `len` is returned unchanged.
+pub fn reflect(
+  buf: &mut [u8],
+  len: usize,
+  seq: u32,
+  recv_clock: u64,
+  send_clock: u64,
+  sender_ttl: u8,
+) -> usize {
+  if len < SENDER_HEAD_SIZE || buf.len() < REFLECTOR_HEAD_SIZE {
+    return len;
+  }
+  let mut sender = [0u8; SENDER_HEAD_SIZE];
+  sender.copy_from_slice(&buf[..SENDER_HEAD_SIZE]);
+  buf[0..4].copy_from_slice(&seq.to_be_bytes());
+  buf[4..12].copy_from_slice(&ntp_from_clock(send_clock).to_be_bytes());
+  buf[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+  buf[14..16].fill(0);
+  buf[16..24].copy_from_slice(&ntp_from_clock(recv_clock).to_be_bytes());
+  buf[24..38].copy_from_slice(&sender);
+  buf[38..40].fill(0);
+  buf[40] = sender_ttl;
+  len.max(REFLECTOR_HEAD_SIZE)
+}
+
+/// Parse a reflected test packet into our own header, with `send_time`
+/// relative to `start_clock`, the wall clock time the test started at.
+/// Returns `None` if the packet is too short, or was sent before the start.
+pub fn parse_reflected(buf: &[u8], start_clock: u64) -> Option<PacketHeader> {
+  if buf.len() < REFLECTOR_HEAD_SIZE {
+    return None;
+  }
+  let send_clock = clock_from_ntp(u64_at(buf, 28));
+  let since_start = Duration::from_nanos(send_clock.checked_sub(start_clock)?);
+  Some(PacketHeader {
+    index: u32_at(buf, 24) as u64,
+    send_time: get_time_value_from_duration(since_start),
+    send_clock,
+    reflect_recv_clock: clock_from_ntp(u64_at(buf, 16)),
+    reflect_send_clock: clock_from_ntp(u64_at(buf, 4)),
+  })
+}